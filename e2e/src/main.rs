@@ -16,12 +16,51 @@ struct Tracking {
 use rumqttc::{ AsyncClient, MqttOptions };
 use tokio::sync::{ broadcast, Mutex };
 
+/// Maximum time to wait for the Hub to recover after a broker restart
+const RECONNECT_TIMEOUT_SECS: u64 = 60;
+
+/// Address of the MQTT over websocket listener of the broker
+const WEBSOCKET_URL: &str = "ws://127.0.0.1:8083/mqtt";
+
+/// Maximum time to wait for the retained settings over the websocket
+const WEBSOCKET_TIMEOUT_SECS: u64 = 10;
+
+/// Configuration for the Hub, the broker is managed by the tests
+/// so it can be restarted while the Hub is running
+/// The Hub uses its own namespace, as if the broker served several sites
+const HUB_CONFIG: &str = r#"{"broker":"external","topics":{"prefix":"garden-a","settings":"settings"}}"#;
+
+/// Topic prefix of the Hub modules, as configured in `HUB_CONFIG`
+const PREFIX: &str = "garden-a";
+
+/// Prefix of the retained settings of the Hub modules, followed by the setting
+const SETTINGS_PREFIX: &str = "settings/garden-a/";
+
 /// Spawn the Hub
 fn spawn_hub() -> io::Result<Child> {
+    let config_path = std::env::temp_dir().join("terratap-e2e-hub.json");
+    std::fs::write(&config_path, HUB_CONFIG)?;
+
     Command::new("cargo")
         .arg("run")
         .arg("--bin")
         .arg("hub")
+        .env("HUB_CONFIG", config_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+}
+
+/// Spawn the MQTT broker
+fn spawn_broker() -> io::Result<Child> {
+    Command::new("cargo")
+        .arg("run")
+        .arg("--bin")
+        .arg("mqttd")
+        .arg("--")
+        .arg("--ws-listen")
+        .arg("127.0.0.1:8083")
+        .arg("--allow-anonymous")
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()
@@ -41,6 +80,9 @@ async fn main() {
     let cloned_tracking = Arc::clone(&tracking);
 
     tracing::info!("Starting integration test using Simulated versions of the Clients");
+    tracing::info!("Starting the Broker...");
+    let mut broker = spawn_broker().expect("Failed to start broker");
+    tracing::info!("Broker started successfully");
     tracing::info!("Starting the Hub...");
     let mut hub = spawn_hub().expect("Failed to start hub");
     tracing::info!("Hub started successfully");
@@ -59,40 +101,38 @@ async fn main() {
         loop {
             tokio::select! {
                 result = eventloop.poll() => {
-                    if let Ok(notification) = result {
-                        match notification {
-                            rumqttc::Event::Incoming(incoming) => {
-                                match incoming {
-                                    rumqttc::Incoming::Publish(publish) => {
-                                        let topic = publish.topic;
-                                        let payload = publish.payload;
-                                        
-                                        let mut tlock = cloned_tracking.lock().await;
-                                        if topic.starts_with("settings/") {
-                                            let name = topic.trim_start_matches("settings/");
-                                            tracing::info!("Received setting on topic '{}': {}", name, std::str::from_utf8(&payload).unwrap());
-                                            tlock.settings_received += 1;
-                                        } else if topic.ends_with("/response") {
-                                            let name_without_response = topic.trim_end_matches("/response");
-                                            let parsed = std::str::from_utf8(&payload).unwrap();
-                                            tracing::info!("Received response on topic '{}': {}", name_without_response, parsed);
-                                            tlock.responses_received += 1;
-                                            if parsed == "true" {
-                                                tlock.watering_needed_responses += 1;
-                                            }
-                                        }
-                                        drop(tlock);
+                    if let Ok(rumqttc::Event::Incoming(incoming)) = result {
+                        match incoming {
+                            rumqttc::Incoming::Publish(publish) => {
+                                let topic = publish.topic;
+                                let payload = publish.payload;
+                                
+                                let mut tlock = cloned_tracking.lock().await;
+                                if topic.starts_with(SETTINGS_PREFIX) {
+                                    let name = topic.trim_start_matches(SETTINGS_PREFIX);
+                                    tracing::info!("Received setting on topic '{}': {}", name, std::str::from_utf8(&payload).unwrap());
+                                    tlock.settings_received += 1;
+                                } else if topic.ends_with("/response") {
+                                    let name_without_response = topic.trim_end_matches("/response");
+                                    let parsed = std::str::from_utf8(&payload).unwrap();
+                                    tracing::info!("Received response on topic '{}': {}", name_without_response, parsed);
+                                    tlock.responses_received += 1;
+                                    if parsed == "true" {
+                                        tlock.watering_needed_responses += 1;
                                     }
-                                    rumqttc::Incoming::ConnAck(_) => {
-                                        tracing::info!("Connected to MQTT broker");
-                                        client_clone.subscribe("#", rumqttc::QoS::ExactlyOnce).await.unwrap();
-                                        tracing::info!("Subscribed to all topics");
-                                    }
-                                    _ => {}
                                 }
+                                drop(tlock);
+                            }
+                            rumqttc::Incoming::ConnAck(_) => {
+                                tracing::info!("Connected to MQTT broker");
+                                client_clone.subscribe("#", rumqttc::QoS::ExactlyOnce).await.unwrap();
+                                tracing::info!("Subscribed to all topics");
                             }
                             _ => {}
                         }
+                    } else if result.is_err() {
+                        // the broker is not reachable (yet), retry in a bit
+                        tokio::time::sleep(Duration::from_secs(1)).await;
                     }
                 }
                 _ = shutdown_rx.recv() => {
@@ -122,14 +162,14 @@ async fn main() {
     let mut watering_test_passed = 0;
 
     let tlock = tracking.lock().await;
-    let current_responses = tlock.responses_received.clone();
+    let current_responses = tlock.responses_received;
     drop(tlock);
 
     watering_test_count += 1;
     tracing::info!("Sending a message to the HUB to request the state");
     client
         .publish(
-            "home/watering/watering_needed",
+            &format!("{}/watering/watering_needed", PREFIX),
             rumqttc::QoS::AtMostOnce,
             false,
             "".as_bytes()
@@ -155,14 +195,14 @@ async fn main() {
     let mut sensor_test_count = 0;
     let mut sensor_test_passed = 0;
     let tlock = tracking.lock().await;
-    let current_watering_responses = tlock.watering_needed_responses.clone();
-    let current_responses = tlock.responses_received.clone();
+    let current_watering_responses = tlock.watering_needed_responses;
+    let current_responses = tlock.responses_received;
     drop(tlock);
     sensor_test_count += 1;
     tracing::info!("Sending a message to the hub to confirm watering is needed");
     client
         .publish(
-            "home/sensor/watering_needed",
+            &format!("{}/sensor/watering_needed", PREFIX),
             rumqttc::QoS::AtMostOnce,
             false,
             "true".as_bytes()
@@ -173,7 +213,7 @@ async fn main() {
     tracing::info!("Test if message was sent successfully, by requesting the watering state");
     client
         .publish(
-            "home/watering/watering_needed",
+            &format!("{}/watering/watering_needed", PREFIX),
             rumqttc::QoS::AtMostOnce,
             false,
             "".as_bytes()
@@ -199,6 +239,101 @@ async fn main() {
     tracing::info!("Sensor tests completed");
     tracing::info!("Sensor tests passed: {}/{}", sensor_test_passed, sensor_test_count);
 
+    // ################
+    // Websocket Tests
+    tracing::info!("---------------- Websocket Tests ----------------");
+    let mut websocket_test_count = 0;
+    let mut websocket_test_passed = 0;
+
+    websocket_test_count += 1;
+    tracing::info!("Connecting a dashboard over the websocket listener...");
+    let mut ws_options = MqttOptions::new("e2e-websocket", WEBSOCKET_URL, 8083);
+    ws_options.set_transport(rumqttc::Transport::Ws);
+    ws_options.set_keep_alive(Duration::from_secs(5));
+    let (ws_client, mut ws_eventloop) = AsyncClient::new(ws_options, 10);
+    ws_client.subscribe(format!("{}#", SETTINGS_PREFIX), rumqttc::QoS::AtLeastOnce).await.unwrap();
+
+    // the settings are retained, so they are delivered right after subscribing
+    let retained_settings = tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        let mut received = 0;
+        while received < SETTINGS_COUNT {
+            match ws_eventloop.poll().await {
+                Ok(rumqttc::Event::Incoming(rumqttc::Incoming::Publish(publish))) if publish.retain => {
+                    tracing::info!("Received retained setting over websocket on topic '{}'", publish.topic);
+                    received += 1;
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    tracing::error!("Websocket connection failed: {}", error);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }).await;
+    if retained_settings.is_ok() {
+        // because all settings were received over the websocket
+        websocket_test_passed += 1;
+    }
+    ws_client.disconnect().await.unwrap();
+    tracing::info!("Websocket tests completed");
+    tracing::info!("Websocket tests passed: {}/{}", websocket_test_passed, websocket_test_count);
+
+    // ################
+    // Reconnect Tests
+    tracing::info!("---------------- Reconnect Tests ----------------");
+    let mut reconnect_test_count = 0;
+    let mut reconnect_test_passed = 0;
+
+    tracing::info!("Killing the Broker while the Hub is running...");
+    broker.kill().unwrap();
+    broker.wait().unwrap();
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    // the restarted broker has no retained messages, so every setting seen from now on was re-published
+    tracking.lock().await.settings_received = 0;
+
+    tracing::info!("Restarting the Broker...");
+    broker = spawn_broker().expect("Failed to restart broker");
+
+    reconnect_test_count += 1;
+    tracing::info!("Waiting for the Hub to re-publish its settings...");
+    let mut waited = 0;
+    let mut settings_received = 0;
+    while settings_received < SETTINGS_COUNT && waited < RECONNECT_TIMEOUT_SECS {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        waited += 1;
+        settings_received = tracking.lock().await.settings_received;
+    }
+    if settings_received >= SETTINGS_COUNT {
+        // because the hub reconnected and re-published the settings
+        reconnect_test_passed += 1;
+    }
+
+    reconnect_test_count += 1;
+    let current_responses = tracking.lock().await.responses_received;
+    tracing::info!("Requesting the watering state to check the Hub re-subscribed");
+    client
+        .publish(
+            &format!("{}/watering/watering_needed", PREFIX),
+            rumqttc::QoS::AtMostOnce,
+            false,
+            "".as_bytes()
+        ).await
+        .unwrap();
+
+    let mut waited = 0;
+    let mut responses_received = current_responses;
+    while responses_received == current_responses && waited < RECONNECT_TIMEOUT_SECS {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        waited += 1;
+        responses_received = tracking.lock().await.responses_received;
+    }
+    if responses_received > current_responses {
+        // because the hub received the request over its restored subscription
+        reconnect_test_passed += 1;
+    }
+    tracing::info!("Reconnect tests completed");
+    tracing::info!("Reconnect tests passed: {}/{}", reconnect_test_passed, reconnect_test_count);
+
     // ################
     // Cleanup
     tracing::info!("---------------- Cleanup ----------------");
@@ -208,11 +343,16 @@ async fn main() {
     tracing::info!("MQTT client shut down successfully");
     tracing::info!("Killing the Hub...");
     hub.kill().unwrap();
+    hub.wait().unwrap();
     tracing::info!("Hub killed successfully");
+    tracing::info!("Killing the Broker...");
+    broker.kill().unwrap();
+    broker.wait().unwrap();
+    tracing::info!("Broker killed successfully");
     tracing::info!("E2E test completed");
     tracing::info!(
         "Tests passed: {}/{}",
-        watering_test_passed + sensor_test_passed,
-        watering_test_count + sensor_test_count
+        watering_test_passed + sensor_test_passed + websocket_test_passed + reconnect_test_passed,
+        watering_test_count + sensor_test_count + websocket_test_count + reconnect_test_count
     );
 }
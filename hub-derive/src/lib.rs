@@ -0,0 +1,375 @@
+//! Derive macros for the modules of the hub
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{ parse_macro_input, spanned::Spanned, Data, DeriveInput, Fields, LitBool, LitInt, LitStr };
+
+/// Implement `ModuleSettings` for a module with named fields, the crate has to depend on `hub`
+///
+/// Every field is a setting, serialized with serde so its attributes apply
+/// (`rename`, `rename_all` and the `skip` attributes, names that differ between serializing and
+/// deserializing are rejected).
+/// `#[settings(topic = "sensor")]` on the struct adds the constant `TOPIC`.
+/// `#[setting(qos = 1, retain = false)]` changes how a setting is published (QoS 2, retained by default),
+/// `#[setting(skip)]` leaves a field out, like fields skipped by serde.
+#[proc_macro_derive(ModuleSettings, attributes(settings, setting))]
+pub fn derive_module_settings(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+/// A field that is published as a setting
+struct SettingField {
+    ident: syn::Ident,
+    /// Name of the setting, the serialized name of the field
+    name: String,
+    qos: u8,
+    retain: bool,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut topic = None;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("settings")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("topic") {
+                topic = Some(meta.value()?.parse::<LitStr>()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `topic`"))
+            }
+        })?;
+    }
+
+    let settings = settings(&input)?;
+    let pushes = settings.iter().map(|setting| {
+        let name = &setting.name;
+        let retain = setting.retain;
+        let qos = match setting.qos {
+            0 => quote!(::hub::__private::QoS::AtMostOnce),
+            1 => quote!(::hub::__private::QoS::AtLeastOnce),
+            _ => quote!(::hub::__private::QoS::ExactlyOnce),
+        };
+        quote! {
+            if let Some(value) = value.get(#name) {
+                settings.push(::hub::Setting::new(#name, value, #qos, #retain));
+            }
+        }
+    });
+    let updates = settings.iter().map(|setting| {
+        let (name, field) = (&setting.name, &setting.ident);
+        quote! {
+            #name => {
+                self.#field = ::hub::traits::update_field(self, name, payload, |updated: Self| updated.#field)?;
+                Ok(())
+            }
+        }
+    });
+    let topic = topic.map(|topic| {
+        quote! {
+            impl #impl_generics #ident #type_generics #where_clause {
+                /// Topic of the module below the topic prefix of the hub
+                pub const TOPIC: &'static str = #topic;
+            }
+        }
+    });
+
+    // modules without settings do not have to be serializable
+    let collect = if settings.is_empty() {
+        quote!(::std::vec::Vec::new())
+    } else {
+        quote! {
+            let value = ::hub::__private::serde_json::to_value(self).expect("Failed to serialize the module settings");
+            let mut settings = ::std::vec::Vec::new();
+            #(#pushes)*
+            settings
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::hub::ModuleSettings for #ident #type_generics #where_clause {
+            fn settings(&self) -> ::std::vec::Vec<::hub::Setting> {
+                #collect
+            }
+
+            fn update_setting(&mut self, name: &str, payload: &str) -> ::std::io::Result<()> {
+                match name {
+                    #(#updates)*
+                    _ => Err(::std::io::Error::new(
+                        ::std::io::ErrorKind::NotFound,
+                        format!("unknown setting '{}'", name)
+                    )),
+                }
+            }
+        }
+
+        #topic
+    })
+}
+
+/// The fields of the struct that are settings
+fn settings(input: &DeriveInput) -> syn::Result<Vec<SettingField>> {
+    let rename_all = rename_all(&input.attrs)?;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields.named.iter().collect(),
+            Fields::Unit => Vec::new(),
+            Fields::Unnamed(_) => {
+                return Err(syn::Error::new(input.span(), "ModuleSettings needs named fields"));
+            }
+        },
+        _ => {
+            return Err(syn::Error::new(input.span(), "ModuleSettings can only be derived for structs"));
+        }
+    };
+
+    let mut settings = Vec::new();
+    for field in fields {
+        if let Some(setting) = setting_field(field, rename_all)? {
+            settings.push(setting);
+        }
+    }
+    Ok(settings)
+}
+
+/// Naming convention of a container `#[serde(rename_all = "...")]`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RenameRule {
+    Lower,
+    Upper,
+    Pascal,
+    Camel,
+    Snake,
+    ScreamingSnake,
+    Kebab,
+    ScreamingKebab,
+}
+
+impl RenameRule {
+    fn parse(rule: &LitStr) -> syn::Result<Self> {
+        Ok(match rule.value().as_str() {
+            "lowercase" => Self::Lower,
+            "UPPERCASE" => Self::Upper,
+            "PascalCase" => Self::Pascal,
+            "camelCase" => Self::Camel,
+            "snake_case" => Self::Snake,
+            "SCREAMING_SNAKE_CASE" => Self::ScreamingSnake,
+            "kebab-case" => Self::Kebab,
+            "SCREAMING-KEBAB-CASE" => Self::ScreamingKebab,
+            _ => {
+                return Err(syn::Error::new(rule.span(), "unknown rename rule"));
+            }
+        })
+    }
+
+    /// The serialized name of a field, like serde renames it
+    fn apply(self, field: &str) -> String {
+        match self {
+            Self::Lower | Self::Snake => field.to_string(),
+            Self::Upper | Self::ScreamingSnake => field.to_ascii_uppercase(),
+            Self::Pascal => {
+                let mut pascal = String::new();
+                let mut capitalize = true;
+                for ch in field.chars() {
+                    if ch == '_' {
+                        capitalize = true;
+                    } else if capitalize {
+                        pascal.push(ch.to_ascii_uppercase());
+                        capitalize = false;
+                    } else {
+                        pascal.push(ch);
+                    }
+                }
+                pascal
+            }
+            Self::Camel => {
+                let pascal = Self::Pascal.apply(field);
+                pascal[..1].to_ascii_lowercase() + &pascal[1..]
+            }
+            Self::Kebab => field.replace('_', "-"),
+            Self::ScreamingKebab => field.to_ascii_uppercase().replace('_', "-"),
+        }
+    }
+}
+
+/// The value of `rename` or `rename_all`, which has to be the same for serializing and deserializing
+/// as the settings are read back under the name they were published with
+fn rename_value(meta: &syn::meta::ParseNestedMeta) -> syn::Result<LitStr> {
+    if meta.input.peek(syn::Token![=]) {
+        return meta.value()?.parse::<LitStr>();
+    }
+
+    let (mut serialize, mut deserialize) = (None, None);
+    meta.parse_nested_meta(|nested| {
+        let value = nested.value()?.parse::<LitStr>()?;
+        if nested.path.is_ident("serialize") {
+            serialize = Some(value);
+        } else if nested.path.is_ident("deserialize") {
+            deserialize = Some(value);
+        }
+        Ok(())
+    })?;
+    match (serialize, deserialize) {
+        (Some(serialize), Some(deserialize)) if serialize.value() == deserialize.value() => Ok(serialize),
+        _ => Err(meta.error("ModuleSettings needs the same name for serializing and deserializing")),
+    }
+}
+
+/// Skip the value of a serde attribute that does not matter for the settings
+fn skip_value(meta: &syn::meta::ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(syn::Token![=]) {
+        meta.value()?.parse::<syn::Expr>()?;
+    } else if meta.input.peek(syn::token::Paren) {
+        let _nested;
+        syn::parenthesized!(_nested in meta.input);
+    }
+    Ok(())
+}
+
+/// The `rename_all` rule of the container, if any
+fn rename_all(attrs: &[syn::Attribute]) -> syn::Result<Option<RenameRule>> {
+    let mut rule = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename_all") {
+                rule = Some(RenameRule::parse(&rename_value(&meta)?)?);
+                Ok(())
+            } else {
+                skip_value(&meta)
+            }
+        })?;
+    }
+    Ok(rule)
+}
+
+/// The setting of a field, `None` if it is skipped
+fn setting_field(field: &syn::Field, rename_all: Option<RenameRule>) -> syn::Result<Option<SettingField>> {
+    let ident = field.ident.clone().unwrap();
+    let name = rename_all.map_or_else(|| ident.to_string(), |rule| rule.apply(&ident.to_string()));
+    let mut setting = SettingField { name, ident, qos: 2, retain: true };
+
+    for attr in &field.attrs {
+        if attr.path().is_ident("setting") {
+            let mut skip = false;
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip = true;
+                } else if meta.path.is_ident("qos") {
+                    let qos = meta.value()?.parse::<LitInt>()?;
+                    setting.qos = qos.base10_parse()?;
+                    if setting.qos > 2 {
+                        return Err(syn::Error::new(qos.span(), "the QoS has to be 0, 1 or 2"));
+                    }
+                } else if meta.path.is_ident("retain") {
+                    setting.retain = meta.value()?.parse::<LitBool>()?.value;
+                } else {
+                    return Err(meta.error("expected `skip`, `qos` or `retain`"));
+                }
+                Ok(())
+            })?;
+            if skip {
+                return Ok(None);
+            }
+        } else if attr.path().is_ident("serde") {
+            // the field is only a setting if serde writes and reads it under its name
+            let mut skip = false;
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") || meta.path.is_ident("skip_serializing") || meta.path.is_ident("skip_deserializing") {
+                    skip = true;
+                } else if meta.path.is_ident("rename") {
+                    setting.name = rename_value(&meta)?.value();
+                } else {
+                    skip_value(&meta)?;
+                }
+                Ok(())
+            })?;
+            if skip {
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(Some(setting))
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::parse_quote;
+
+    use super::*;
+
+    /// Names of the settings of the struct
+    fn names(input: DeriveInput) -> Vec<String> {
+        settings(&input).unwrap().into_iter().map(|setting| setting.name).collect()
+    }
+
+    #[test]
+    fn test_field_attributes() {
+        let input = parse_quote! {
+            struct Module {
+                check_time: String,
+                #[serde(rename = "duration", default)]
+                check_duration: u64,
+                #[serde(skip)]
+                cache: Vec<u64>,
+                #[setting(skip)]
+                prefix: String,
+            }
+        };
+        assert_eq!(names(input), vec!["check_time", "duration"]);
+    }
+
+    #[test]
+    fn test_container_rename_all() {
+        let input = parse_quote! {
+            #[serde(rename_all = "camelCase", deny_unknown_fields)]
+            struct Module {
+                check_time: String,
+                #[serde(rename = "open")]
+                open_duration: u64,
+            }
+        };
+        assert_eq!(names(input), vec!["checkTime", "open"]);
+
+        let input = parse_quote! {
+            #[serde(rename_all(serialize = "kebab-case", deserialize = "kebab-case"))]
+            struct Module {
+                check_time: String,
+            }
+        };
+        assert_eq!(names(input), vec!["check-time"]);
+        assert_eq!(RenameRule::Pascal.apply("check_time"), "CheckTime");
+        assert_eq!(RenameRule::ScreamingKebab.apply("check_time"), "CHECK-TIME");
+    }
+
+    #[test]
+    fn test_names_have_to_round_trip() {
+        let input: DeriveInput = parse_quote! {
+            #[serde(rename_all(serialize = "camelCase"))]
+            struct Module {
+                check_time: String,
+            }
+        };
+        assert!(settings(&input).is_err());
+
+        let input: DeriveInput = parse_quote! {
+            struct Module {
+                #[serde(rename(serialize = "time", deserialize = "check_time"))]
+                check_time: String,
+            }
+        };
+        assert!(settings(&input).is_err());
+
+        let input: DeriveInput = parse_quote! {
+            #[serde(rename_all = "Title Case")]
+            struct Module {
+                check_time: String,
+            }
+        };
+        let error = expand(input).unwrap_err();
+        assert_eq!(error.to_string(), "unknown rename rule");
+    }
+}
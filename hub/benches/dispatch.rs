@@ -0,0 +1,99 @@
+//! Throughput of the message dispatch of the `ModuleManager`
+//!
+//! Run with `cargo bench -p hub --bench dispatch`.
+//! Fast modules handle their messages while a slow module works through its backlog,
+//! handing the messages off has to stay quick so the event loop of the client keeps polling.
+
+use std::{
+    sync::{ atomic::{ AtomicU64, Ordering }, Arc },
+    time::{ Duration, Instant },
+};
+
+use hub::{ topic, ClientModule, Handlers, HubContext, ModuleError, ModuleManager, ModuleSettings };
+
+/// Messages for every fast module
+const MESSAGES: u64 = 100_000;
+/// Fast modules, each handles its messages right away
+const FAST_MODULES: u64 = 4;
+/// Messages for the slow module
+const SLOW_MESSAGES: u64 = 50;
+/// Time the slow module takes for every message
+const SLOW_DELAY: Duration = Duration::from_millis(20);
+
+#[derive(ModuleSettings)]
+struct BenchModule {
+    #[setting(skip)]
+    name: String,
+    #[setting(skip)]
+    delay: Duration,
+    #[setting(skip)]
+    handled: Arc<AtomicU64>,
+}
+
+impl BenchModule {
+    async fn work(&self, _context: &HubContext, _job: u64) -> Result<(), ModuleError> {
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        self.handled.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl ClientModule for BenchModule {
+    fn topic(&self) -> String {
+        topic!("bench", self.name)
+    }
+
+    fn handlers() -> Handlers<Self> {
+        Handlers::new().on("work", Self::work)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let fast = Arc::new(AtomicU64::new(0));
+    let slow = Arc::new(AtomicU64::new(0));
+    let mut manager = ModuleManager::default();
+    manager.register_module(BenchModule { name: "slow".to_string(), delay: SLOW_DELAY, handled: Arc::clone(&slow) });
+    for index in 0..FAST_MODULES {
+        manager.register_module(BenchModule { name: format!("fast-{}", index), delay: Duration::ZERO, handled: Arc::clone(&fast) });
+    }
+
+    let start = Instant::now();
+    // the slow module gets its backlog first, it must not hold up the others
+    for job in 0..SLOW_MESSAGES {
+        manager.handle_message("bench/slow/work", &job.to_string());
+    }
+    let mut slowest_hand_off = Duration::ZERO;
+    for job in 0..MESSAGES {
+        for index in 0..FAST_MODULES {
+            let hand_off = Instant::now();
+            manager.handle_message(&format!("bench/fast-{}/work", index), &job.to_string());
+            slowest_hand_off = slowest_hand_off.max(hand_off.elapsed());
+        }
+    }
+    let queued = start.elapsed();
+
+    let total = MESSAGES * FAST_MODULES;
+    while fast.load(Ordering::Relaxed) < total {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let fast_done = start.elapsed();
+    let slow_handled = slow.load(Ordering::Relaxed);
+
+    manager.drain().await;
+    let slow_done = start.elapsed();
+
+    println!("queued {} messages in {:?}, slowest hand-off {:?}", total + SLOW_MESSAGES, queued, slowest_hand_off);
+    println!(
+        "fast modules: {} messages in {:?} ({:.0} messages/s), the slow module handled {}/{} by then",
+        total,
+        fast_done,
+        total as f64 / fast_done.as_secs_f64(),
+        slow_handled,
+        SLOW_MESSAGES
+    );
+    println!("slow module: {} messages in {:?}", SLOW_MESSAGES, slow_done);
+}
@@ -0,0 +1,408 @@
+use std::{ collections::VecDeque, io };
+
+use rumqttc::{ AsyncClient, Event, Incoming, MqttOptions, Publish, QoS };
+use serde::{ Deserialize, Serialize };
+use tokio::{ sync::broadcast::Receiver, time::Instant };
+
+use crate::{ config::{ MqttConfig, TopicsConfig }, mqttc::{ Backoff, ConnectionState }, topic };
+
+/// Mirrors local topics to an upstream broker, for example a central broker of several gardens
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BridgeConfig {
+    /// Connection to the upstream broker, the client id has to be unique there
+    pub upstream: MqttConfig,
+    /// Prefix of this site on the upstream broker, like `gardens/backyard`
+    pub prefix: String,
+    /// Local topic filters that are forwarded to the upstream broker,
+    /// everything below the topic prefix of the hub if empty
+    #[serde(default)]
+    pub forward: Vec<String>,
+    /// Topic filters below the prefix on the upstream broker that are relayed to the local broker,
+    /// like commands for the valves
+    #[serde(default)]
+    pub relay: Vec<String>,
+    /// Maximum number of messages kept while the upstream broker is unreachable,
+    /// the oldest ones are dropped first
+    #[serde(default = "default_buffer")]
+    pub buffer: usize,
+}
+
+fn default_buffer() -> usize {
+    1000
+}
+
+/// Maximum number of relayed messages remembered until the local broker echoes them,
+/// the oldest ones are forgotten first
+const MAX_ECHOES: usize = 100;
+
+/// A message waiting to be forwarded
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+/// State of the bridge between the two brokers
+struct Bridge {
+    config: BridgeConfig,
+    upstream: ConnectionState,
+    /// Messages that could not be forwarded yet
+    buffered: VecDeque<Message>,
+    /// Topics and payloads relayed to the local broker, they come back over the forwarded
+    /// filters and must not be sent upstream again
+    echoes: VecDeque<(String, Vec<u8>)>,
+}
+
+impl Bridge {
+    fn new(config: BridgeConfig) -> Self {
+        Self {
+            config,
+            upstream: ConnectionState::Disconnected,
+            buffered: VecDeque::new(),
+            echoes: VecDeque::new(),
+        }
+    }
+
+    /// Topic of a local message on the upstream broker
+    fn upstream_topic(&self, topic: &str) -> String {
+        format!("{}/{}", self.config.prefix, topic)
+    }
+
+    /// Topic of an upstream message on the local broker, `None` if it is not below the prefix
+    fn local_topic<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic.strip_prefix(&self.config.prefix)?.strip_prefix('/')
+    }
+
+    /// Remember a relayed message, so it is not forwarded back
+    /// Only messages on forwarded topics come back, the others are not remembered
+    fn relayed(&mut self, topic: &str, payload: &[u8]) {
+        if !self.config.forward.iter().any(|filter| mqttd::acl::matches(filter, topic)) {
+            return;
+        }
+        if self.echoes.len() >= MAX_ECHOES {
+            self.echoes.pop_front();
+        }
+        self.echoes.push_back((topic.to_string(), payload.to_vec()));
+    }
+
+    /// Whether a local message is one that was relayed from upstream, it is forgotten afterwards
+    fn is_echo(&mut self, topic: &str, payload: &[u8]) -> bool {
+        let echo = self.echoes.iter().position(|(relayed, relayed_payload)| relayed == topic && relayed_payload == payload);
+        echo.and_then(|index| self.echoes.remove(index)).is_some()
+    }
+
+    /// Queue a local message for the upstream broker
+    fn buffer(&mut self, message: Message) {
+        if self.buffered.len() >= self.config.buffer {
+            if let Some(dropped) = self.buffered.pop_front() {
+                tracing::warn!("Bridge buffer is full, dropping the message on '{}'", dropped.topic);
+            }
+        }
+        if self.config.buffer > 0 {
+            self.buffered.push_back(message);
+        }
+    }
+
+    /// Hand the buffered messages to the upstream client while it is connected
+    /// Messages the client can not take right now stay buffered
+    fn flush(&mut self, upstream: &AsyncClient) {
+        if self.upstream != ConnectionState::Connected {
+            return;
+        }
+        while let Some(message) = self.buffered.pop_front() {
+            let topic = self.upstream_topic(&message.topic);
+            if upstream.try_publish(topic, QoS::AtLeastOnce, message.retain, message.payload.clone()).is_err() {
+                self.buffered.push_front(message);
+                break;
+            }
+        }
+    }
+
+    /// A message of the local broker, forwarded unless it was relayed from upstream
+    fn local_message(&mut self, publish: Publish, upstream: &AsyncClient) {
+        if self.is_echo(&publish.topic, &publish.payload) {
+            return;
+        }
+        self.buffer(Message { topic: publish.topic, payload: publish.payload.to_vec(), retain: publish.retain });
+        self.flush(upstream);
+    }
+
+    /// A message of the upstream broker, relayed to the local broker without the prefix
+    fn upstream_message(&mut self, publish: Publish, local: &AsyncClient) {
+        let Some(topic) = self.local_topic(&publish.topic).map(str::to_string) else {
+            return;
+        };
+        tracing::info!("Relaying '{}' from the upstream broker", topic);
+        self.relayed(&topic, &publish.payload);
+        if let Err(error) = local.try_publish(&topic, QoS::AtLeastOnce, false, publish.payload.to_vec()) {
+            tracing::error!("Failed to relay '{}' to the local broker: {}", topic, error);
+            self.is_echo(&topic, &publish.payload);
+        }
+    }
+}
+
+/// The bridge together with the options of both of its connections
+pub struct Connections {
+    config: BridgeConfig,
+    local: MqttConfig,
+    local_options: MqttOptions,
+    upstream_options: MqttOptions,
+}
+
+impl Connections {
+    /// Prepare the connections of the bridge, checked while the hub is built
+    /// `local` is the connection of the hub, the bridge connects with its own client id
+    /// Fails if the TLS certificates of a connection can not be read
+    pub fn new(mut config: BridgeConfig, local: &MqttConfig, topics: &TopicsConfig) -> io::Result<Self> {
+        if config.forward.is_empty() {
+            config.forward.push(topic!(topics.prefix, "#"));
+        }
+        let local = MqttConfig { client_id: format!("{}-bridge", local.client_id), ..local.clone() };
+        let local_options = MqttOptions::try_from(&local)?;
+        let upstream_options = MqttOptions::try_from(&config.upstream)
+            .map_err(|error| io::Error::new(error.kind(), format!("upstream broker of the bridge: {}", error)))?;
+        Ok(Self { config, local, local_options, upstream_options })
+    }
+}
+
+/// Run the bridge until the shutdown signal
+pub fn run(connections: Connections, mut shutdown: Receiver<()>) -> tokio::task::JoinHandle<()> {
+    let Connections { config, local, local_options, upstream_options } = connections;
+    tracing::info!(
+        "Bridging {:?} to {}:{} below '{}'",
+        config.forward,
+        config.upstream.host,
+        config.upstream.port,
+        config.prefix
+    );
+
+    let (local_client, mut local_loop) = AsyncClient::new(local_options, local.capacity);
+    let (upstream_client, mut upstream_loop) = AsyncClient::new(upstream_options, config.upstream.capacity);
+    let mut local_backoff = Backoff::new(
+        std::time::Duration::from_millis(local.reconnect_delay),
+        std::time::Duration::from_millis(local.max_reconnect_delay)
+    );
+    let mut upstream_backoff = Backoff::new(
+        std::time::Duration::from_millis(config.upstream.reconnect_delay),
+        std::time::Duration::from_millis(config.upstream.max_reconnect_delay)
+    );
+    let mut bridge = Bridge::new(config);
+    // each event loop waits out its own backoff, so the other one keeps running meanwhile
+    let (mut local_retry, mut upstream_retry): (Option<Instant>, Option<Instant>) = (None, None);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                result = local_loop.poll(), if local_retry.is_none() => {
+                    match result {
+                        Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                            local_backoff.reset();
+                            for filter in &bridge.config.forward {
+                                let _ = local_client.try_subscribe(filter, QoS::AtLeastOnce);
+                            }
+                        }
+                        Ok(Event::Incoming(Incoming::Publish(publish))) => bridge.local_message(publish, &upstream_client),
+                        Ok(_) => bridge.flush(&upstream_client),
+                        Err(error) => {
+                            let delay = local_backoff.next_delay();
+                            tracing::error!("Bridge lost the local broker: {}, retrying in {:?}", error, delay);
+                            local_retry = Some(Instant::now() + delay);
+                        }
+                    }
+                }
+                _ = tokio::time::sleep_until(local_retry.unwrap_or_else(Instant::now)), if local_retry.is_some() => {
+                    local_retry = None;
+                }
+                result = upstream_loop.poll(), if upstream_retry.is_none() => {
+                    match result {
+                        Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                            tracing::info!("Bridge connected to the upstream broker");
+                            upstream_backoff.reset();
+                            bridge.upstream = ConnectionState::Connected;
+                            for filter in &bridge.config.relay {
+                                let _ = upstream_client.try_subscribe(bridge.upstream_topic(filter), QoS::AtLeastOnce);
+                            }
+                            bridge.flush(&upstream_client);
+                        }
+                        Ok(Event::Incoming(Incoming::Publish(publish))) => bridge.upstream_message(publish, &local_client),
+                        Ok(_) => bridge.flush(&upstream_client),
+                        Err(error) => {
+                            bridge.upstream = ConnectionState::Disconnected;
+                            let delay = upstream_backoff.next_delay();
+                            tracing::warn!(
+                                "Bridge lost the upstream broker: {}, buffering {} messages, retrying in {:?}",
+                                error,
+                                bridge.buffered.len(),
+                                delay
+                            );
+                            upstream_retry = Some(Instant::now() + delay);
+                        }
+                    }
+                }
+                _ = tokio::time::sleep_until(upstream_retry.unwrap_or_else(Instant::now)), if upstream_retry.is_some() => {
+                    upstream_retry = None;
+                }
+                _ = shutdown.recv() => {
+                    tracing::info!("Shutting down the bridge...");
+                    break;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ net::TcpListener, time::Duration };
+
+    use super::*;
+
+    fn config() -> BridgeConfig {
+        BridgeConfig {
+            upstream: MqttConfig::default(),
+            prefix: "gardens/backyard".to_string(),
+            forward: vec!["home/#".to_string()],
+            relay: vec!["home/watering/+".to_string()],
+            buffer: 2,
+        }
+    }
+
+    fn message(topic: &str) -> Message {
+        Message { topic: topic.to_string(), payload: b"true".to_vec(), retain: false }
+    }
+
+    #[test]
+    fn test_topics_are_prefixed() {
+        let bridge = Bridge::new(config());
+        assert_eq!(bridge.upstream_topic("home/sensor/watering_needed"), "gardens/backyard/home/sensor/watering_needed");
+        assert_eq!(bridge.local_topic("gardens/backyard/home/watering/start"), Some("home/watering/start"));
+        assert_eq!(bridge.local_topic("gardens/backyardx/home/watering/start"), None);
+    }
+
+    #[test]
+    fn test_buffer_drops_oldest() {
+        let mut bridge = Bridge::new(config());
+        bridge.buffer(message("home/sensor/1"));
+        bridge.buffer(message("home/sensor/2"));
+        bridge.buffer(message("home/sensor/3"));
+
+        let topics = bridge.buffered.iter().map(|message| message.topic.as_str()).collect::<Vec<_>>();
+        assert_eq!(topics, vec!["home/sensor/2", "home/sensor/3"]);
+    }
+
+    #[test]
+    fn test_relayed_messages_are_not_echoed() {
+        let mut bridge = Bridge::new(config());
+        bridge.relayed("home/watering/start", b"10");
+
+        assert!(!bridge.is_echo("home/watering/start", b"20"));
+        assert!(bridge.is_echo("home/watering/start", b"10"));
+        // the same message published locally later is forwarded again
+        assert!(!bridge.is_echo("home/watering/start", b"10"));
+    }
+
+    #[test]
+    fn test_echoes_are_limited() {
+        let mut bridge = Bridge::new(BridgeConfig { forward: vec!["home/watering/#".to_string()], ..config() });
+        // not forwarded, so it never comes back
+        bridge.relayed("office/watering/start", b"10");
+        assert!(bridge.echoes.is_empty());
+
+        for index in 0..MAX_ECHOES + 10 {
+            bridge.relayed("home/watering/start", index.to_string().as_bytes());
+        }
+        assert_eq!(bridge.echoes.len(), MAX_ECHOES);
+        assert!(!bridge.is_echo("home/watering/start", b"0"));
+        assert!(bridge.is_echo("home/watering/start", (MAX_ECHOES + 9).to_string().as_bytes()));
+    }
+
+    #[test]
+    fn test_connections_need_readable_certificates() {
+        let mut config = config();
+        config.upstream.tls = Some(crate::config::TlsConfig {
+            ca: std::env::temp_dir().join("hub-test-missing-ca.pem"),
+            client_cert: None,
+            client_key: None,
+        });
+        let error = Connections::new(config, &MqttConfig::default(), &TopicsConfig::default()).err().unwrap();
+        assert!(error.to_string().starts_with("upstream broker of the bridge"));
+    }
+
+    /// Start a broker on a free port
+    async fn start_broker() -> u16 {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut broker_config = mqttd::default_config();
+        broker_config.v5 = None;
+        mqttd::Overrides {
+            v4_listen: Some(format!("127.0.0.1:{}", port).parse().unwrap()),
+            ..Default::default()
+        }
+            .apply(&mut broker_config)
+            .unwrap();
+        let broker = mqttd::start(broker_config, mqttd::default_extensions(), None).unwrap();
+        broker.ready(Duration::from_secs(5)).await.unwrap();
+        port
+    }
+
+    /// Wait for the next message on the topic
+    async fn receive(eventloop: &mut rumqttc::EventLoop, topic: &str) -> Vec<u8> {
+        let receive = async {
+            loop {
+                if let Event::Incoming(Incoming::Publish(publish)) = eventloop.poll().await.unwrap() {
+                    if publish.topic == topic {
+                        return publish.payload.to_vec();
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), receive).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bridge_between_two_brokers() {
+        let (local_port, upstream_port) = (start_broker().await, start_broker().await);
+        let local = MqttConfig { port: local_port, client_id: "hub-bridge-test".to_string(), ..Default::default() };
+        let mut config = config();
+        config.upstream = MqttConfig { port: upstream_port, client_id: "backyard".to_string(), ..Default::default() };
+
+        let (central, mut central_loop) = AsyncClient::new(MqttOptions::new("central", "127.0.0.1", upstream_port), 10);
+        central.subscribe("gardens/+/home/#", QoS::AtLeastOnce).await.unwrap();
+        let (device, mut device_loop) = AsyncClient::new(MqttOptions::new("device", "127.0.0.1", local_port), 10);
+        device.subscribe("home/watering/+", QoS::AtLeastOnce).await.unwrap();
+        // the subscriptions are in place once the acknowledgements arrived
+        for eventloop in [&mut central_loop, &mut device_loop] {
+            while !matches!(eventloop.poll().await.unwrap(), Event::Incoming(Incoming::SubAck(_))) {}
+        }
+        let (sensor, mut sensor_loop) = AsyncClient::new(MqttOptions::new("sensor", "127.0.0.1", local_port), 10);
+        tokio::spawn(async move {
+            loop {
+                let _ = sensor_loop.poll().await;
+            }
+        });
+
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let connections = Connections::new(config, &local, &TopicsConfig::default()).unwrap();
+        let _bridge = run(connections, shutdown_rx);
+
+        // the bridge connects in the background, publish until the first message made it upstream
+        let mut attempts = 0;
+        let forwarded = loop {
+            sensor.publish("home/sensor/watering_needed", QoS::AtLeastOnce, false, "true").await.unwrap();
+            let attempt = receive(&mut central_loop, "gardens/backyard/home/sensor/watering_needed");
+            if let Ok(payload) = tokio::time::timeout(Duration::from_millis(500), attempt).await {
+                break payload;
+            }
+            attempts += 1;
+            assert!(attempts < 20, "nothing was forwarded to the upstream broker");
+        };
+        assert_eq!(forwarded, b"true");
+
+        central.publish("gardens/backyard/home/watering/start", QoS::AtLeastOnce, false, "10").await.unwrap();
+        let relayed = tokio::select! {
+            payload = receive(&mut device_loop, "home/watering/start") => payload,
+            _ = async { loop { let _ = central_loop.poll().await; } } => unreachable!(),
+        };
+        assert_eq!(relayed, b"10");
+    }
+}
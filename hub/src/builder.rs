@@ -0,0 +1,500 @@
+use std::{
+    io,
+    path::{ Path, PathBuf },
+    process::{ self, Child, Stdio },
+    sync::Arc,
+    time::Duration,
+};
+
+use rumqttc::MqttOptions;
+use tokio::sync::{ broadcast, Mutex };
+
+use crate::{
+    bridge,
+    config::{ BrokerMode, Config, ModulesConfig },
+    mqttc,
+    outbox::Outbox,
+    ClientModule,
+    ConfigFile,
+    HubContext,
+    ModuleManager,
+    Settings,
+    State,
+    SystemClock,
+};
+
+/// Creates a module once the topic prefix and the settings of the hub are known,
+/// it is registered if the configuration enables it
+type ModuleFactory = Box<dyn FnOnce(&mut Loader, &str, &Settings) + Send>;
+
+/// Builds a hub with its modules
+///
+/// The value of a module setting comes from the first of these that sets it:
+/// 1. `modules.load.<name>.settings` of the configuration (`config.json`)
+/// 2. the section of the module in the settings (`settings.json`, migrated if it has the legacy layout)
+/// 3. the shared `defaults` of the settings
+/// 4. the default of the module
+///
+/// The settings are saved as they were loaded, the configuration is not written into them
+///
+/// ```no_run
+/// # async fn run(shutdown: tokio::sync::broadcast::Receiver<()>) {
+/// use hub::{ config::{ BrokerMode, Config }, modules::SensorModule, HubBuilder };
+///
+/// let hub = HubBuilder::new(Config::default())
+///     .broker(BrokerMode::External)
+///     .data_dir("/var/lib/terratap")
+///     .module_with(SensorModule::new)
+///     .build()
+///     .expect("Failed to read the TLS certificates");
+/// hub.run(shutdown).await.expect("Failed to start the broker");
+/// # }
+/// ```
+pub struct HubBuilder {
+    config: Config,
+    data_dir: Option<PathBuf>,
+    settings: Option<Settings>,
+    modules: Vec<ModuleFactory>,
+}
+
+impl Default for HubBuilder {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+impl HubBuilder {
+    pub fn new(config: Config) -> Self {
+        Self { config, data_dir: None, settings: None, modules: Vec::new() }
+    }
+
+    /// How the hub gets hold of a MQTT broker, overrides the configuration
+    pub fn broker(mut self, mode: BrokerMode) -> Self {
+        self.config.broker = mode;
+        self
+    }
+
+    /// Directory of the state, the settings and a relative outbox path, overrides the configuration
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Use these settings instead of loading them from the data directory
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Register a module that is already created, unless the configuration disables it
+    pub fn module<M: ClientModule + 'static>(mut self, module: M) -> Self {
+        self.modules.push(Box::new(move |loader, _, _| loader.load(module)));
+        self
+    }
+
+    /// Register a module created from the topic prefix and the settings of the hub,
+    /// like `SensorModule::new`, unless the configuration disables it
+    pub fn module_with<M, F>(mut self, create: F) -> Self
+        where M: ClientModule + 'static, F: FnOnce(&str, &Settings) -> M + Send + 'static
+    {
+        self.modules.push(Box::new(move |loader, prefix, settings| loader.load(create(prefix, settings))));
+        self
+    }
+
+    /// Load the state, settings and queued publications and register the modules
+    /// Fails if the TLS certificates of the client or the bridge can not be read
+    pub fn build(self) -> io::Result<Hub> {
+        let HubBuilder { mut config, data_dir, settings, modules } = self;
+        // the connections are made once the hub runs, their certificates are read now
+        MqttOptions::try_from(&config.mqtt)?;
+        let bridge = match config.bridge.clone() {
+            Some(bridge) => Some(bridge::Connections::new(bridge, &config.mqtt, &config.topics)?),
+            None => None,
+        };
+        let data_dir = data_dir.or_else(|| config.data_dir.clone()).unwrap_or_default();
+        if let Err(error) = std::fs::create_dir_all(&data_dir) {
+            tracing::error!("Failed to create the data directory '{}': {}", data_dir.display(), error);
+        }
+        let state_path = data_dir.join(State::PATH);
+        let settings_path = data_dir.join(Settings::PATH);
+        config.outbox.path = config.outbox.path.map(|path| data_dir.join(path));
+
+        // the modules get the state and settings through the context
+        let settings = settings.unwrap_or_else(|| load_or_default::<Settings>(&settings_path));
+        let outbox = Outbox::open(config.outbox.clone());
+        let context = HubContext::new(load_or_default::<State>(&state_path), settings.clone(), outbox, SystemClock);
+
+        // Register the modules below the topic namespace of the hub
+        let mut manager = ModuleManager::new(context.clone());
+        manager.set_settings_prefix(&config.topics.settings);
+        manager.set_publish_errors(config.modules.publish_errors);
+        let mut loader = Loader { manager, config: &config.modules, disabled: Vec::new() };
+        for register in modules {
+            register(&mut loader, &config.topics.prefix, &settings);
+        }
+        let Loader { manager, disabled, .. } = loader;
+
+        let loaded = manager.module_names();
+        tracing::info!("Loaded modules: {}", loaded.join(", "));
+        if !disabled.is_empty() {
+            tracing::info!("Disabled modules: {}", disabled.join(", "));
+        }
+        for name in config.modules.load.iter().flat_map(|load| load.keys()) {
+            if !loaded.contains(name) && !disabled.contains(name) {
+                tracing::warn!("The configuration lists the module '{}', but the hub has no such module", name);
+            }
+        }
+
+        Ok(Hub { config, context, manager, bridge, state_path, settings_path })
+    }
+}
+
+/// Load a file of the data directory, starting with the defaults if it can not be read
+fn load_or_default<C: ConfigFile<&'static str>>(path: &Path) -> C::Config {
+    C::load_from(path).unwrap_or_else(|error| {
+        tracing::error!("Failed to load '{}', starting with the defaults: {}", path.display(), error);
+        C::Config::default()
+    })
+}
+
+/// Registers the modules the configuration enables, with their settings from the configuration
+struct Loader<'a> {
+    manager: ModuleManager,
+    config: &'a ModulesConfig,
+    /// Names of the modules that are not loaded
+    disabled: Vec<String>,
+}
+
+impl Loader<'_> {
+    /// Register the module if it is enabled, with the settings of the configuration on top of its own,
+    /// see `HubBuilder` for the precedence
+    fn load<M: ClientModule + 'static>(&mut self, mut module: M) {
+        let name = module.name();
+        if !self.config.is_enabled(&name) {
+            self.disabled.push(name);
+            return;
+        }
+        for (setting, value) in self.config.settings(&name).into_iter().flatten() {
+            // the settings are read like their payloads, strings without quotes
+            let payload = match value {
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            if let Err(error) = module.update_setting(setting, &payload) {
+                tracing::error!("Ignoring the setting '{}' of module '{}' in the configuration: {}", setting, name, error);
+            }
+        }
+        self.manager.register_module(module);
+    }
+}
+
+/// A hub with its modules, ready to run
+pub struct Hub {
+    config: Config,
+    context: HubContext,
+    manager: ModuleManager,
+    bridge: Option<bridge::Connections>,
+    state_path: PathBuf,
+    settings_path: PathBuf,
+}
+
+impl Hub {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The context the modules of the hub get passed
+    pub fn context(&self) -> &HubContext {
+        &self.context
+    }
+
+    /// Start the broker, the modules and the client and run until the shutdown signal is received
+    /// The state and settings are saved before it returns
+    /// Fails right away if the broker can not be started, before the modules are started
+    pub async fn run(self, mut shutdown_rx: broadcast::Receiver<()>) -> io::Result<()> {
+        let Hub { config, context, manager, bridge, state_path, settings_path } = self;
+        let broker = start_broker(&config).await?;
+        manager.start().await;
+        // share the manager between the client and the ticks
+        let manager = Arc::new(Mutex::new(manager));
+
+        tracing::info!("TerraTap running... Press Ctrl+C to exit.");
+
+        // the tasks of the hub are stopped by the hub itself, also when the client stops on its own
+        let (stop, _) = broadcast::channel(1);
+        let bridge_task = bridge.map(|bridge| bridge::run(bridge, stop.subscribe()));
+        let tick_task = match config.modules.tick_interval {
+            0 => None,
+            seconds => Some(spawn_ticks(Arc::clone(&manager), Duration::from_secs(seconds), stop.subscribe())),
+        };
+        let mut client_task = mqttc::run(&config.mqtt, Arc::clone(&manager), stop.subscribe()).await;
+
+        // Wait for either the shutdown signal or the client task to finish
+        // The client task should not finish, it reconnects on its own if the broker goes away
+        let client_stopped = tokio::select! {
+            _ = shutdown_rx.recv() => false,
+            _ = &mut client_task => {
+                tracing::error!("The MQTT client stopped, shutting down...");
+                true
+            }
+        };
+        // stop the other tasks however the hub is shut down
+        let _ = stop.send(());
+        if !client_stopped {
+            let _ = client_task.await;
+        }
+        if let Some(bridge_task) = bridge_task {
+            let _ = bridge_task.await;
+        }
+        if let Some(tick_task) = tick_task {
+            let _ = tick_task.await;
+        }
+        // let the modules finish their work while the state can still be changed
+        manager.lock().await.shutdown().await;
+        // kill the broker to be sure all tasks are cleaned up
+        if let Some(mut broker) = broker {
+            let _ = broker.kill();
+        }
+        // Save the state and settings before exiting
+        let saved = context.state().lock().await.save_to(&state_path);
+        if let Err(error) = saved {
+            tracing::error!("Failed to save the state to '{}': {}", state_path.display(), error);
+        }
+        let saved = context.settings().lock().await.save_to(&settings_path);
+        if let Err(error) = saved {
+            tracing::error!("Failed to save the settings to '{}': {}", settings_path.display(), error);
+        }
+        Ok(())
+    }
+}
+
+/// The error of a broker configuration that can not be loaded
+fn invalid_broker_config(error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid broker configuration: {}", error))
+}
+
+/// Spawn the MQTT broker installed next to the hub binary as a child process
+/// The access control of the broker follows the topics of the hub
+fn spawn_broker(config: &Config) -> io::Result<Child> {
+    let binary = std::env
+        ::current_exe()?
+        .with_file_name(format!("mqttd{}", std::env::consts::EXE_SUFFIX));
+
+    let mut command = process::Command::new(&binary);
+    if let Some(broker_config) = &config.broker_config {
+        command.arg("--config").arg(broker_config);
+    }
+    if let Some(credentials) = &config.credentials {
+        command.arg("--credentials").arg(credentials);
+    }
+    if config.allow_anonymous {
+        command.arg("--allow-anonymous");
+    }
+    command
+        .arg("--topic-prefix")
+        .arg(&config.topics.prefix)
+        .arg("--settings-topic")
+        .arg(&config.topics.settings);
+    command
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", binary.display(), error)))
+}
+
+/// How long to wait for the embedded broker to accept connections
+const EMBEDDED_BROKER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a spawned broker to accept connections
+const SPAWNED_BROKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Start the MQTT broker according to the configuration
+/// Returns once the broker accepts connections, so the client can connect right away
+/// A spawned broker is returned, to kill it on shutdown
+/// Fails if the configuration of the broker is invalid or the broker can not be started
+async fn start_broker(config: &Config) -> io::Result<Option<Child>> {
+    match config.broker {
+        BrokerMode::Embedded => {
+            tracing::info!("Starting embedded MQTT broker...");
+            let mut broker_config = mqttd::load_config(config.broker_config.as_deref()).map_err(invalid_broker_config)?;
+            let mut extensions = mqttd::load_extensions(config.broker_config.as_deref()).map_err(invalid_broker_config)?;
+            // the access control follows the topics of the hub
+            extensions.acl.prefix = config.topics.prefix.clone();
+            extensions.acl.settings = config.topics.settings.clone();
+            let credentials = mqttd::auth::configure(&mut broker_config, config.credentials.as_deref(), None, config.allow_anonymous)?;
+            let broker = mqttd::start(broker_config, extensions, credentials)?;
+            if let Err(error) = broker.ready(EMBEDDED_BROKER_TIMEOUT).await {
+                tracing::error!("Embedded MQTT broker is not ready: {}", error);
+            }
+            Ok(None)
+        }
+        BrokerMode::Spawn => {
+            tracing::info!("Starting MQTT broker...");
+            let broker = spawn_broker(config)?;
+
+            let addr = tokio::net
+                ::lookup_host((config.mqtt.host.as_str(), config.mqtt.port)).await
+                .ok()
+                .and_then(|mut addrs| addrs.next());
+            if let Some(addr) = addr {
+                let ready = mqttd::wait_until_ready(addr, SPAWNED_BROKER_TIMEOUT);
+                if let Err(error) = ready.await {
+                    tracing::error!("Spawned MQTT broker is not ready: {}", error);
+                }
+            }
+            Ok(Some(broker))
+        }
+        BrokerMode::External => {
+            tracing::info!("Using external MQTT broker");
+            Ok(None)
+        }
+    }
+}
+
+/// Tick the modules every interval until the shutdown signal is received
+fn spawn_ticks(
+    manager: Arc<Mutex<ModuleManager>>,
+    interval: Duration,
+    mut shutdown_rx: broadcast::Receiver<()>
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        // the first tick completes immediately, the modules were just started
+        ticks.tick().await;
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => manager.lock().await.tick().await,
+                _ = shutdown_rx.recv() => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::{ SensorModule, WateringModule, WateringSettings };
+
+    #[tokio::test]
+    async fn test_run_creates_the_data_dir_and_saves_on_shutdown() {
+        let dir = std::env::temp_dir().join("hub-test-run/data");
+        let _ = std::fs::remove_dir_all(dir.parent().unwrap());
+        let mut config = Config::default();
+        // nothing listens there, the client keeps reconnecting until the shutdown
+        config.mqtt.port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        config.outbox.path = None;
+
+        let hub = HubBuilder::new(config).broker(BrokerMode::External).data_dir(&dir).build().unwrap();
+        assert!(dir.is_dir());
+
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let run = tokio::spawn(hub.run(shutdown_rx));
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), run).await.unwrap().unwrap().unwrap();
+        assert!(dir.join(State::PATH).is_file());
+        assert!(dir.join(Settings::PATH).is_file());
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_fails_with_an_invalid_broker_configuration() {
+        let dir = std::env::temp_dir().join("hub-test-invalid-broker");
+        std::fs::create_dir_all(&dir).unwrap();
+        let broker_config = dir.join("rumqttd.toml");
+        std::fs::write(&broker_config, "[v4.1\nname = ").unwrap();
+        let mut config = Config { broker_config: Some(broker_config), ..Config::default() };
+        config.outbox.path = None;
+
+        let hub = HubBuilder::new(config).broker(BrokerMode::Embedded).data_dir(&dir).build().unwrap();
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let error = hub.run(shutdown_rx).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("invalid broker configuration"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_build_from_data_dir() {
+        let dir = std::env::temp_dir().join("hub-test-data-dir");
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings = serde_json::from_str::<Settings>(r#"{"sensor":{"check_duration":45}}"#).unwrap();
+        settings.save_to(dir.join(Settings::PATH)).unwrap();
+
+        let hub = HubBuilder::default()
+            .broker(BrokerMode::External)
+            .data_dir(&dir)
+            .module_with(SensorModule::new)
+            .build().unwrap();
+
+        assert_eq!(hub.config().broker, BrokerMode::External);
+        assert_eq!(hub.config().outbox.path, Some(dir.join("outbox.json")));
+        assert_eq!(hub.context().settings().lock().await.modules["sensor"]["check_duration"], 45);
+        assert_eq!(hub.manager.setting("home/sensor/check_duration").unwrap().value, "45");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_configuration_selects_the_modules() {
+        let config = serde_json::from_str::<Config>(
+            r#"{"modules":{"load":{"sensor":{"settings":{"check_duration":45,"check_time":"04:00"}},"watering":{"enabled":false}}}}"#
+        ).unwrap();
+        let hub = HubBuilder::new(config)
+            .settings(Settings::default())
+            .module_with(SensorModule::new)
+            .module_with(WateringModule::new)
+            .build().unwrap();
+
+        assert_eq!(hub.manager.module_names(), vec!["sensor"]);
+        assert_eq!(hub.manager.setting("home/sensor/check_duration").unwrap().value, "45");
+        assert_eq!(hub.manager.setting("home/sensor/check_time").unwrap().value, "04:00");
+    }
+
+    #[test]
+    fn test_all_modules_are_loaded_by_default() {
+        let hub = HubBuilder::default()
+            .settings(Settings::default())
+            .module_with(SensorModule::new)
+            .module_with(WateringModule::new)
+            .build().unwrap();
+        assert_eq!(hub.manager.module_names(), vec!["sensor", "watering"]);
+    }
+
+    #[tokio::test]
+    async fn test_configuration_wins_over_the_settings() {
+        let config = serde_json::from_str::<Config>(
+            r#"{"modules":{"load":{"sensor":{"settings":{"check_duration":60}},"watering":{}}}}"#
+        ).unwrap();
+        let settings = serde_json::from_str::<Settings>(
+            r#"{"defaults":{"check_time":"03:30"},"sensor":{"check_time":"02:00","check_duration":45}}"#
+        ).unwrap();
+        let hub = HubBuilder::new(config)
+            .settings(settings.clone())
+            .module_with(SensorModule::new)
+            .module_with(WateringModule::new)
+            .build()
+            .unwrap();
+
+        let setting = |topic: &str| hub.manager.setting(topic).unwrap().value.clone();
+        assert_eq!(setting("home/sensor/check_duration"), "60");
+        assert_eq!(setting("home/sensor/check_time"), "02:00");
+        assert_eq!(setting("home/watering/check_time"), "03:30");
+        assert_eq!(setting("home/watering/open_duration"), "300");
+        // the configuration does not end up in the saved settings
+        assert_eq!(*hub.context().settings().lock().await, settings);
+    }
+
+    #[tokio::test]
+    async fn test_settings_override_the_data_dir() {
+        let mut settings = Settings::default();
+        let watering = WateringSettings { open_duration: 10, ..WateringSettings::default() };
+        settings.set_module("watering", &watering).unwrap();
+        let dir = std::env::temp_dir().join("hub-test-settings-override");
+        let _ = std::fs::remove_dir_all(&dir);
+        let hub = HubBuilder::default().data_dir(&dir).settings(settings).module_with(WateringModule::new).build().unwrap();
+        assert_eq!(hub.manager.setting("home/watering/open_duration").unwrap().value, "10");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
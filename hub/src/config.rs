@@ -0,0 +1,401 @@
+use std::{ collections::BTreeMap, io, path::{ Path, PathBuf }, time::Duration };
+
+use rumqttc::{ MqttOptions, Transport };
+use serde::{ Deserialize, Serialize };
+
+use crate::{ bridge::BridgeConfig, outbox::OutboxConfig, traits::ConfigFile };
+
+/// Environment variable that can point the hub to a different configuration file
+pub const CONFIG_ENV: &str = "HUB_CONFIG";
+
+/// Configuration of the hub itself
+/// Unlike the `Settings` these are not shared with the clients over MQTT
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub broker: BrokerMode,
+    /// rumqttd configuration for the embedded or spawned broker
+    /// The broker searches its standard locations if not set
+    pub broker_config: Option<PathBuf>,
+    /// Credential store of the devices, used by the broker and `hub devices`
+    /// The standard locations are searched if not set
+    pub credentials: Option<PathBuf>,
+    /// Let clients connect to the embedded or spawned broker without authentication
+    /// if there is no credential store, otherwise the broker does not start
+    pub allow_anonymous: bool,
+    /// Directory of the state, the settings and a relative outbox path
+    /// Defaults to the directory of the configuration file, or the working directory without one
+    pub data_dir: Option<PathBuf>,
+    pub mqtt: MqttConfig,
+    pub topics: TopicsConfig,
+    pub outbox: OutboxConfig,
+    pub modules: ModulesConfig,
+    /// Forward local topics to an upstream broker, disabled if not set
+    pub bridge: Option<BridgeConfig>,
+}
+
+/// How the hub gets hold of a MQTT broker
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BrokerMode {
+    /// Run the `mqttd` broker inside the hub process
+    #[default]
+    Embedded,
+    /// Spawn the `mqttd` broker as a child process
+    Spawn,
+    /// Connect to a broker that is managed outside of the hub
+    External,
+}
+
+/// Topic namespace of the hub, so one broker can serve several sites
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TopicsConfig {
+    /// Prefix of the module topics, like `garden-a` for `garden-a/sensor`
+    pub prefix: String,
+    /// Prefix of the retained settings, followed by the module topic
+    pub settings: String,
+}
+
+impl Default for TopicsConfig {
+    fn default() -> Self {
+        Self {
+            prefix: crate::modules::PREFIX.to_string(),
+            settings: crate::core::SETTINGS_PREFIX.to_string(),
+        }
+    }
+}
+
+/// How the hub runs its modules
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModulesConfig {
+    /// Seconds between the ticks of the modules, 0 disables the ticks
+    pub tick_interval: u64,
+    /// Publish the failures of the module handlers on the `errors` topic of the module
+    pub publish_errors: bool,
+    /// The modules to load by their name, all modules of the hub are loaded if not set
+    pub load: Option<BTreeMap<String, ModuleConfig>>,
+}
+
+impl Default for ModulesConfig {
+    fn default() -> Self {
+        Self { tick_interval: 60, publish_errors: false, load: None }
+    }
+}
+
+impl ModulesConfig {
+    /// Whether the module is loaded, listed modules are enabled unless turned off
+    pub fn is_enabled(&self, name: &str) -> bool {
+        match &self.load {
+            Some(load) => load.get(name).is_some_and(|module| module.enabled),
+            None => true,
+        }
+    }
+
+    /// The settings of the module from the configuration, they win over the settings of the hub,
+    /// see `HubBuilder` for the precedence
+    pub fn settings(&self, name: &str) -> Option<&serde_json::Map<String, serde_json::Value>> {
+        self.load.as_ref()?.get(name).map(|module| &module.settings)
+    }
+}
+
+/// A module in the configuration of the hub
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModuleConfig {
+    pub enabled: bool,
+    /// Settings of the module by their name, like `{"check_duration": 45}`
+    pub settings: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Default for ModuleConfig {
+    fn default() -> Self {
+        Self { enabled: true, settings: serde_json::Map::new() }
+    }
+}
+
+/// Connection settings for the MQTT client of the hub
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// Keep alive interval in seconds
+    pub keep_alive: u64,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub clean_session: bool,
+    /// Maximum number of outgoing QoS 1 and 2 publications in flight
+    pub inflight: u16,
+    /// Capacity of the request channel between the client and the event loop
+    pub capacity: usize,
+    /// Delay before the first reconnection attempt in milliseconds
+    pub reconnect_delay: u64,
+    /// Upper bound for the exponential reconnection backoff in milliseconds
+    pub max_reconnect_delay: u64,
+    /// Connect over TLS instead of plain TCP
+    pub tls: Option<TlsConfig>,
+}
+
+/// TLS settings for the connection to the broker
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// CA the broker certificate has to be issued by, the system roots are not trusted
+    pub ca: PathBuf,
+    /// Client certificate, for brokers that authenticate clients by certificate
+    pub client_cert: Option<PathBuf>,
+    /// Private key of the client certificate
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Read the certificates and build the TLS transport
+    pub fn transport(&self) -> io::Result<Transport> {
+        let ca = std::fs::read(&self.ca)?;
+        let client_auth = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((std::fs::read(cert)?, std::fs::read(key)?)),
+            (None, None) => None,
+            _ => {
+                return Err(
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "client_cert and client_key have to be set together"
+                    )
+                );
+            }
+        };
+
+        Ok(Transport::tls(ca, client_auth, None))
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            client_id: "hub".to_string(),
+            keep_alive: 5,
+            username: None,
+            password: None,
+            clean_session: true,
+            inflight: 100,
+            capacity: 10,
+            reconnect_delay: 500,
+            max_reconnect_delay: 30_000,
+            tls: None,
+        }
+    }
+}
+
+impl TryFrom<&MqttConfig> for MqttOptions {
+    type Error = io::Error;
+
+    fn try_from(config: &MqttConfig) -> io::Result<Self> {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(config.keep_alive));
+        options.set_clean_session(config.clean_session);
+        options.set_inflight(config.inflight);
+        options.set_request_channel_capacity(config.capacity);
+
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        if let Some(tls) = &config.tls {
+            options.set_transport(tls.transport()?);
+        }
+
+        Ok(options)
+    }
+}
+
+impl Config {
+    /// Locate the configuration file
+    /// An explicit path wins over `HUB_CONFIG`, otherwise the working directory
+    /// and the standard configuration directories are searched
+    pub fn locate(path: Option<&Path>) -> Option<PathBuf> {
+        if let Some(path) = path {
+            return Some(path.to_path_buf());
+        }
+        if let Some(path) = std::env::var_os(CONFIG_ENV) {
+            return Some(PathBuf::from(path));
+        }
+
+        std::iter
+            ::once(PathBuf::from(Self::PATH))
+            .chain(mqttd::config_dirs().into_iter().map(|dir| dir.join(Self::PATH)))
+            .find(|path| path.is_file())
+    }
+
+    /// Load the configuration from the located file or use the defaults
+    /// Fails if the located file can not be read or is invalid
+    pub fn resolve(path: Option<&Path>) -> io::Result<Self> {
+        match Self::locate(path) {
+            Some(path) => {
+                tracing::info!("Loading configuration from '{}'", path.display());
+                let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+                let config = Self::load_from(&path)
+                    .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))?;
+                Ok(config.relative_to(&dir))
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Resolve the paths of the configuration against the directory of its file,
+    /// so the hub does not depend on the working directory it was started from
+    fn relative_to(mut self, dir: &Path) -> Self {
+        self.data_dir = Some(self.data_dir.map_or_else(|| dir.to_path_buf(), |path| dir.join(path)));
+        self.broker_config = self.broker_config.map(|path| dir.join(path));
+        self.credentials = self.credentials
+            .map(|path| dir.join(path))
+            .or_else(|| mqttd::auth::locate_credentials(None, Some(dir)));
+        self
+    }
+}
+
+impl ConfigFile<&'static str> for Config {
+    const PATH: &'static str = "config.json";
+    type Config = Self;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_config_uses_defaults() {
+        let config = serde_json::from_str::<Config>(r#"{"mqtt":{"port":1885}}"#).unwrap();
+        assert_eq!(config.broker, BrokerMode::Embedded);
+        assert_eq!(config.mqtt.port, 1885);
+        assert_eq!(config.mqtt.host, "127.0.0.1");
+        assert_eq!(config.mqtt.client_id, "hub");
+        assert_eq!(config.topics, TopicsConfig::default());
+        assert_eq!(config.outbox.path, Some(PathBuf::from("outbox.json")));
+        assert_eq!(config.modules.tick_interval, 60);
+        assert!(!config.modules.publish_errors);
+        assert!(config.modules.is_enabled("watering"));
+    }
+
+    #[test]
+    fn test_modules_to_load() {
+        let config = serde_json::from_str::<Config>(
+            r#"{"modules":{"load":{"sensor":{"settings":{"check_duration":45}},"watering":{"enabled":false}}}}"#
+        ).unwrap();
+        assert!(config.modules.is_enabled("sensor"));
+        assert!(!config.modules.is_enabled("watering"));
+        assert!(!config.modules.is_enabled("pump"));
+        assert_eq!(config.modules.settings("sensor").unwrap()["check_duration"], 45);
+        assert!(config.modules.settings("pump").is_none());
+    }
+
+    #[test]
+    fn test_topics_namespace() {
+        let config = serde_json::from_str::<Config>(r#"{"topics":{"prefix":"garden-a"}}"#).unwrap();
+        assert_eq!(config.topics.prefix, "garden-a");
+        assert_eq!(config.topics.settings, "settings");
+    }
+
+    #[test]
+    fn test_broker_mode_lowercase() {
+        let config = serde_json::from_str::<Config>(r#"{"broker":"external"}"#).unwrap();
+        assert_eq!(config.broker, BrokerMode::External);
+    }
+
+    #[test]
+    fn test_bridge_defaults() {
+        let config = serde_json::from_str::<Config>(
+            r#"{"bridge":{"upstream":{"host":"central.local","client_id":"backyard"},"prefix":"gardens/backyard"}}"#
+        ).unwrap();
+        let bridge = config.bridge.unwrap();
+        assert_eq!(bridge.upstream.host, "central.local");
+        assert_eq!(bridge.upstream.port, 1883);
+        assert!(bridge.forward.is_empty());
+        assert!(bridge.relay.is_empty());
+        assert_eq!(bridge.buffer, 1000);
+    }
+
+    #[test]
+    fn test_resolve_explicit_path() {
+        let path = std::env::temp_dir().join("hub-test-config.json");
+        std::fs::write(&path, r#"{"broker":"spawn","broker_config":"/etc/rumqttd.toml"}"#).unwrap();
+
+        let config = Config::resolve(Some(&path)).unwrap();
+        assert_eq!(config.broker, BrokerMode::Spawn);
+        assert_eq!(config.broker_config, Some(PathBuf::from("/etc/rumqttd.toml")));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_invalid_file() {
+        let path = std::env::temp_dir().join("hub-test-config-invalid.json");
+        std::fs::write(&path, r#"{"broker":"sometimes"}"#).unwrap();
+
+        let error = Config::resolve(Some(&path)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with(&path.display().to_string()));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_relative_to_the_config_file() {
+        let dir = std::env::temp_dir().join("hub-test-config-dir");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        std::fs::write(&path, r#"{"broker_config":"rumqttd.toml","credentials":"/etc/credentials.toml"}"#).unwrap();
+
+        let config = Config::resolve(Some(&path)).unwrap();
+        assert_eq!(config.data_dir, Some(dir.clone()));
+        assert_eq!(config.broker_config, Some(dir.join("rumqttd.toml")));
+        assert_eq!(config.credentials, Some(PathBuf::from("/etc/credentials.toml")));
+
+        std::fs::write(&path, r#"{"data_dir":"data"}"#).unwrap();
+        assert_eq!(Config::resolve(Some(&path)).unwrap().data_dir, Some(dir.join("data")));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_mqtt_options_from_config() {
+        let config = MqttConfig {
+            host: "broker.local".to_string(),
+            port: 8883,
+            client_id: "garden-hub".to_string(),
+            keep_alive: 30,
+            username: Some("hub".to_string()),
+            password: Some("secret".to_string()),
+            clean_session: false,
+            inflight: 20,
+            capacity: 50,
+            ..Default::default()
+        };
+        let options = MqttOptions::try_from(&config).unwrap();
+
+        assert_eq!(options.broker_address(), ("broker.local".to_string(), 8883));
+        assert_eq!(options.client_id(), "garden-hub");
+        assert_eq!(options.keep_alive(), Duration::from_secs(30));
+        assert_eq!(options.credentials(), Some(("hub".to_string(), "secret".to_string())));
+        assert!(!options.clean_session());
+        assert_eq!(options.inflight(), 20);
+        assert_eq!(options.request_channel_capacity(), 50);
+        assert!(matches!(options.transport(), Transport::Tcp));
+    }
+
+    #[test]
+    fn test_mqtt_options_missing_ca() {
+        let config = MqttConfig {
+            tls: Some(TlsConfig {
+                ca: PathBuf::from("/does/not/exist.pem"),
+                client_cert: None,
+                client_key: None,
+            }),
+            ..Default::default()
+        };
+
+        assert!(MqttOptions::try_from(&config).is_err());
+    }
+}
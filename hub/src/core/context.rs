@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use chrono::{ Local, NaiveDateTime };
+use once_cell::sync::OnceCell;
+use rumqttc::{ AsyncClient, QoS };
+use tokio::sync::Mutex;
+
+use crate::{ outbox::{ Message, Outbox, OutboxConfig }, EventBus, Settings, State };
+
+/// The time as the modules see it
+pub trait Clock: Send + Sync {
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// The local time of the system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+/// Everything the modules get from the hub, passed to their handlers and hooks
+/// Cloning is cheap, the clones share the same hub
+#[derive(Clone)]
+pub struct HubContext {
+    state: Arc<Mutex<State>>,
+    settings: Arc<Mutex<Settings>>,
+    outbox: Arc<Mutex<Outbox>>,
+    /// Set once the client of the hub is created
+    client: Arc<OnceCell<AsyncClient>>,
+    clock: Arc<dyn Clock>,
+    events: Arc<EventBus>,
+}
+
+/// A context that only lives in memory, nothing is published or saved
+impl Default for HubContext {
+    fn default() -> Self {
+        let outbox = OutboxConfig { path: None, ..OutboxConfig::default() };
+        Self::new(State::default(), Settings::default(), Outbox::open(outbox), SystemClock)
+    }
+}
+
+impl HubContext {
+    pub fn new(state: State, settings: Settings, outbox: Outbox, clock: impl Clock + 'static) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            settings: Arc::new(Mutex::new(settings)),
+            outbox: Arc::new(Mutex::new(outbox)),
+            client: Arc::new(OnceCell::new()),
+            clock: Arc::new(clock),
+            events: Arc::new(EventBus::default()),
+        }
+    }
+
+    /// The state of the hub, saved on shutdown
+    pub fn state(&self) -> &Mutex<State> {
+        &self.state
+    }
+
+    /// The settings of the hub, saved on shutdown
+    pub fn settings(&self) -> &Mutex<Settings> {
+        &self.settings
+    }
+
+    /// The publications waiting for the broker
+    pub fn outbox(&self) -> &Mutex<Outbox> {
+        &self.outbox
+    }
+
+    /// Hand the publications of the modules to the client from now on
+    /// The client of a hub is only set once
+    pub fn set_client(&self, client: AsyncClient) {
+        if self.client.set(client).is_err() {
+            tracing::warn!("The hub already has a client, keeping the first one");
+        }
+    }
+
+    /// The events between the modules of the hub
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn now(&self) -> NaiveDateTime {
+        self.clock.now()
+    }
+
+    /// Publish a message that must not get lost
+    /// It is queued and handed to the client right away if connected, otherwise after the next reconnect
+    /// Returns false if the outbox had to drop it
+    pub async fn publish(&self, topic: impl Into<String>, qos: QoS, retain: bool, payload: impl Into<Vec<u8>>) -> bool {
+        let mut outbox = self.outbox.lock().await;
+        let queued = outbox.push(Message::new(topic, qos, retain, payload));
+        if let Some(client) = self.client.get() {
+            outbox.flush(client);
+        }
+        queued
+    }
+}
@@ -0,0 +1,118 @@
+use std::{ error::Error, fmt, io };
+
+/// Why a module failed to handle a message
+#[derive(Debug)]
+pub enum ModuleError {
+    /// A publication of the module did not reach the client or the outbox
+    Publish(String),
+    /// Any other failure of a handler, shown like the error it wraps
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl ModuleError {
+    /// Wrap any error, like `.map_err(ModuleError::other)?`
+    pub fn other(error: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        ModuleError::Other(error.into())
+    }
+
+    /// The error together with its sources, for the logs
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = self.source();
+        while let Some(error) = source {
+            report.push_str(&format!(": {}", error));
+            source = error.source();
+        }
+        report
+    }
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModuleError::Publish(topic) => write!(f, "failed to publish on '{}'", topic),
+            ModuleError::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for ModuleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ModuleError::Publish(_) => None,
+            ModuleError::Other(error) => error.source(),
+        }
+    }
+}
+
+impl From<io::Error> for ModuleError {
+    fn from(error: io::Error) -> Self {
+        ModuleError::other(error)
+    }
+}
+
+impl From<serde_json::Error> for ModuleError {
+    fn from(error: serde_json::Error) -> Self {
+        ModuleError::other(error)
+    }
+}
+
+impl From<Box<dyn Error + Send + Sync>> for ModuleError {
+    fn from(error: Box<dyn Error + Send + Sync>) -> Self {
+        ModuleError::Other(error)
+    }
+}
+
+impl From<String> for ModuleError {
+    fn from(message: String) -> Self {
+        ModuleError::other(message)
+    }
+}
+
+impl From<&str> for ModuleError {
+    fn from(message: &str) -> Self {
+        ModuleError::other(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Error of a custom module with the error it was caused by
+    #[derive(Debug)]
+    struct ValveError(io::Error);
+
+    impl fmt::Display for ValveError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "the valve did not answer")
+        }
+    }
+
+    impl Error for ValveError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    fn open_valve() -> Result<(), ModuleError> {
+        Err(ModuleError::other(ValveError(io::Error::new(io::ErrorKind::TimedOut, "no response within 5s"))))
+    }
+
+    fn read_schedule() -> Result<u64, ModuleError> {
+        Ok(serde_json::from_str::<u64>("soon")?)
+    }
+
+    #[test]
+    fn test_report_shows_the_sources() {
+        let error = open_valve().unwrap_err();
+        assert_eq!(error.to_string(), "the valve did not answer");
+        assert_eq!(error.report(), "the valve did not answer: no response within 5s");
+
+        let error = read_schedule().unwrap_err();
+        assert!(matches!(error, ModuleError::Other(_)));
+        assert_eq!(error.report(), "expected value at line 1 column 1");
+        assert_eq!(ModuleError::from("no schedule").report(), "no schedule");
+        assert_eq!(ModuleError::Publish("home/valve".to_string()).report(), "failed to publish on 'home/valve'");
+    }
+}
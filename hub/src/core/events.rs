@@ -0,0 +1,69 @@
+use std::{ any::{ Any, TypeId }, collections::HashMap, sync::Mutex };
+
+use tokio::sync::broadcast;
+
+/// Events every subscriber can fall behind before it misses the oldest ones
+const CAPACITY: usize = 256;
+
+/// An event of the hub, published and subscribed to by its type
+pub trait Event: Clone + Send + Sync + 'static {}
+
+/// Typed in-process events between the modules of a hub
+#[derive(Default)]
+pub struct EventBus {
+    /// The `broadcast::Sender` of every event type
+    channels: Mutex<HashMap<TypeId, Box<dyn Any + Send + Sync>>>,
+}
+
+impl EventBus {
+    fn sender<E: Event>(&self) -> broadcast::Sender<E> {
+        let mut channels = self.channels.lock().unwrap();
+        channels
+            .entry(TypeId::of::<E>())
+            .or_insert_with(|| Box::new(broadcast::channel::<E>(CAPACITY).0))
+            .downcast_ref::<broadcast::Sender<E>>()
+            .expect("The channel belongs to another event type")
+            .clone()
+    }
+
+    /// Publish an event to the subscribers of its type
+    /// Returns the number of subscribers
+    pub fn publish<E: Event>(&self, event: E) -> usize {
+        self.sender::<E>().send(event).unwrap_or(0)
+    }
+
+    /// Subscribe to the events of a type that are published from now on
+    pub fn subscribe<E: Event>(&self) -> broadcast::Receiver<E> {
+        self.sender::<E>().subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Opened(u64);
+
+    impl Event for Opened {}
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Closed;
+
+    impl Event for Closed {}
+
+    #[tokio::test]
+    async fn test_events_reach_the_subscribers_of_their_type() {
+        let bus = EventBus::default();
+        assert_eq!(bus.publish(Opened(1)), 0);
+
+        let mut opened = bus.subscribe::<Opened>();
+        let mut closed = bus.subscribe::<Closed>();
+        assert_eq!(bus.publish(Opened(30)), 1);
+        assert_eq!(bus.publish(Closed), 1);
+
+        assert_eq!(opened.recv().await.unwrap(), Opened(30));
+        assert_eq!(closed.recv().await.unwrap(), Closed);
+        assert!(opened.try_recv().is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    any::{ type_name, Any, TypeId },
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+};
+
+use serde::de::DeserializeOwned;
+use tokio::{ sync::broadcast::error::RecvError, task::JoinHandle };
+
+use super::{ Event, EventBus, HubContext, ModuleError };
+
+/// The future of a running handler, borrowing the module
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<(), ModuleError>> + Send + 'a>>;
+
+/// A handler for the payloads of one topic,
+/// usually an `async fn(&self, context: &HubContext, payload: T) -> Result<(), ModuleError>` of the module
+pub trait Handler<'a, M: 'a, T>: Send + Sync {
+    type Future: Future<Output = Result<(), ModuleError>> + Send + 'a;
+
+    fn call(&self, module: &'a M, context: &'a HubContext, payload: T) -> Self::Future;
+}
+
+impl<'a, M: 'a, T, F, Fut> Handler<'a, M, T> for F
+    where F: Fn(&'a M, &'a HubContext, T) -> Fut + Send + Sync, Fut: Future<Output = Result<(), ModuleError>> + Send + 'a
+{
+    type Future = Fut;
+
+    fn call(&self, module: &'a M, context: &'a HubContext, payload: T) -> Fut {
+        self(module, context, payload)
+    }
+}
+
+/// A handler with the payload type erased, decodes the payload before calling the handler
+type ErasedHandler<M> = Box<
+    dyn (for<'a> Fn(&'a M, &'a HubContext, &str) -> Result<HandlerFuture<'a>, serde_json::Error>) + Send + Sync
+>;
+
+/// An event on its way to a module, with its type erased
+pub type AnyEvent = Box<dyn Any + Send>;
+
+/// Hands the events of a subscription to a module, false once the module is gone
+pub type EventSink = Arc<dyn Fn(AnyEvent) -> bool + Send + Sync>;
+
+/// A handler with the event type erased, downcasts the event before calling the handler
+type ErasedEventHandler<M> = Box<dyn (for<'a> Fn(&'a M, &'a HubContext, AnyEvent) -> HandlerFuture<'a>) + Send + Sync>;
+
+/// An event handler of a module
+struct EventHandler<M> {
+    /// Type name of the event, for the logs
+    name: &'static str,
+    handler: ErasedEventHandler<M>,
+    /// Forwards the events of the type from the bus to a sink, on the returned task
+    subscribe: fn(&EventBus, EventSink) -> JoinHandle<()>,
+}
+
+/// The handlers of a module, by their topic below the module topic and by their event type
+pub struct Handlers<M> {
+    handlers: HashMap<String, ErasedHandler<M>>,
+    /// Topics the module does not handle, but expects, like its own publications
+    ignored: Vec<String>,
+    events: HashMap<TypeId, EventHandler<M>>,
+}
+
+impl<M> Default for Handlers<M> {
+    fn default() -> Self {
+        Self { handlers: HashMap::new(), ignored: Vec::new(), events: HashMap::new() }
+    }
+}
+
+/// Why a message could not be handled, reported by the `ModuleManager`
+#[derive(Debug)]
+pub enum HandleError {
+    /// No module has a handler for the topic
+    UnknownTopic(String),
+    /// The payload could not be decoded into the type of the handler
+    Malformed { topic: String, error: serde_json::Error },
+    /// The handler of the module failed
+    Failed { module: String, topic: String, error: ModuleError },
+}
+
+impl fmt::Display for HandleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandleError::UnknownTopic(topic) => write!(f, "no handler for '{}'", topic),
+            HandleError::Malformed { topic, error } => write!(f, "malformed payload on '{}': {}", topic, error),
+            HandleError::Failed { module, topic, error } => write!(f, "module '{}' failed on '{}': {}", module, topic, error),
+        }
+    }
+}
+
+impl<M: 'static> Handlers<M> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle the topic below the module topic, the payload is decoded as `T`
+    /// Payloads are read as JSON, or as a string if they are no valid JSON
+    pub fn on<T, H>(mut self, topic: &str, handler: H) -> Self
+        where T: DeserializeOwned + 'static, H: for<'a> Handler<'a, M, T> + 'static
+    {
+        let handler: ErasedHandler<M> = Box::new(move |module, context, payload| {
+            let payload = decode::<T>(payload)?;
+            Ok(Box::pin(handler.call(module, context, payload)))
+        });
+        self.handlers.insert(topic.to_string(), handler);
+        self
+    }
+
+    /// Handle the events of type `E` from the event bus of the hub
+    pub fn event<E, H>(mut self, handler: H) -> Self
+        where E: Event, H: for<'a> Handler<'a, M, E> + 'static
+    {
+        let event_handler = EventHandler {
+            name: type_name::<E>(),
+            handler: Box::new(move |module, context, event| {
+                let event = *event.downcast::<E>().expect("The event belongs to another handler");
+                Box::pin(handler.call(module, context, event))
+            }),
+            subscribe: forward::<E>,
+        };
+        self.events.insert(TypeId::of::<E>(), event_handler);
+        self
+    }
+
+    /// Accept the topic below the module topic without handling it
+    pub fn ignore(mut self, topic: &str) -> Self {
+        self.ignored.push(topic.to_string());
+        self
+    }
+
+    /// Whether there is a handler for the topic below the module topic or it is ignored
+    pub fn handles(&self, topic: &str) -> bool {
+        self.handlers.contains_key(topic) || self.ignored.iter().any(|ignored| ignored == topic)
+    }
+
+    /// Decode the payload and start the handler of the topic below the module topic
+    /// Returns `None` for ignored and unknown topics
+    pub fn call<'a>(
+        &'a self,
+        module: &'a M,
+        context: &'a HubContext,
+        topic: &str,
+        payload: &str
+    ) -> Option<Result<HandlerFuture<'a>, serde_json::Error>> {
+        self.handlers.get(topic).map(|handler| handler(module, context, payload))
+    }
+
+    /// Forward the events the module handles from the bus to the sink
+    /// Returns the forwarding tasks, they run until the sink is gone or they are aborted
+    pub fn subscribe(&self, bus: &EventBus, sink: EventSink) -> Vec<JoinHandle<()>> {
+        self.events.values().map(|event| (event.subscribe)(bus, Arc::clone(&sink))).collect()
+    }
+
+    /// Whether the module handles any events
+    pub fn has_events(&self) -> bool {
+        !self.events.is_empty()
+    }
+
+    /// Start the handler of the event, together with the type name of the event
+    /// Returns `None` if the module does not handle events of its type
+    pub fn call_event<'a>(&'a self, module: &'a M, context: &'a HubContext, event: AnyEvent) -> Option<(&'static str, HandlerFuture<'a>)> {
+        let handler = self.events.get(&(*event).type_id())?;
+        Some((handler.name, (handler.handler)(module, context, event)))
+    }
+}
+
+/// Forward the events of type `E` to the sink, until it is gone
+fn forward<E: Event>(bus: &EventBus, sink: EventSink) -> JoinHandle<()> {
+    let mut events = bus.subscribe::<E>();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if !sink(Box::new(event)) {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => tracing::warn!("Missed {} events of type {}", missed, type_name::<E>()),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Decode a payload as JSON, falling back to a JSON string for plain text
+fn decode<T: DeserializeOwned>(payload: &str) -> Result<T, serde_json::Error> {
+    serde_json::from_str(payload).or_else(|error| {
+        serde_json::from_value(serde_json::Value::String(payload.to_string())).map_err(|_| error)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Valve {
+        opened: Mutex<Vec<u64>>,
+    }
+
+    impl Valve {
+        async fn open(&self, _context: &HubContext, seconds: u64) -> Result<(), ModuleError> {
+            self.opened.lock().unwrap().push(seconds);
+            Ok(())
+        }
+    }
+
+    #[derive(Clone)]
+    struct Rain(u64);
+
+    impl Event for Rain {}
+
+    impl Valve {
+        async fn rain(&self, _context: &HubContext, rain: Rain) -> Result<(), ModuleError> {
+            self.opened.lock().unwrap().retain(|seconds| *seconds > rain.0);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_handler() {
+        let handlers = Handlers::new().event(Valve::rain);
+        let valve = Valve { opened: Mutex::new(vec![10, 60]) };
+        let context = HubContext::default();
+        assert!(handlers.has_events());
+
+        let (name, handler) = handlers.call_event(&valve, &context, Box::new(Rain(30))).unwrap();
+        assert!(name.ends_with("Rain"));
+        handler.await.unwrap();
+        assert_eq!(*valve.opened.lock().unwrap(), vec![60]);
+        // events of other types are not handled
+        assert!(handlers.call_event(&valve, &context, Box::new(30u64)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_typed_handler() {
+        let handlers = Handlers::new().on("open", Valve::open).ignore("open/response");
+        let valve = Valve::default();
+        let context = HubContext::default();
+
+        handlers.call(&valve, &context, "open", "30").unwrap().unwrap().await.unwrap();
+        assert_eq!(*valve.opened.lock().unwrap(), vec![30]);
+
+        assert!(handlers.call(&valve, &context, "open", "soon").unwrap().is_err());
+        assert!(handlers.call(&valve, &context, "close", "").is_none());
+        assert!(handlers.handles("open/response"));
+        assert!(!handlers.handles("close"));
+    }
+
+    #[test]
+    fn test_decode_plain_text() {
+        assert!(decode::<bool>("true").unwrap());
+        assert_eq!(decode::<String>("backyard").unwrap(), "backyard");
+        assert_eq!(decode::<String>("").unwrap(), "");
+        assert!(decode::<bool>("yes").is_err());
+    }
+}
@@ -1,87 +1,405 @@
-use crate::{ topic, ClientModule };
-use std::collections::HashMap;
+use crate::{
+    mqttc::ConnectionState,
+    outbox::Message,
+    topic,
+    ClientModule,
+    AnyEvent,
+    EventSink,
+    HandleError,
+    Handlers,
+    HubContext,
+    Outcome,
+    Publisher,
+    Setting,
+};
+use once_cell::sync::OnceCell;
+use rumqttc::QoS;
+use std::{ collections::HashMap, sync::{ atomic::{ AtomicU64, Ordering }, Arc, Mutex } };
+use tokio::{ sync::{ mpsc::{ self, UnboundedReceiver, UnboundedSender }, oneshot }, task::JoinHandle };
+
+/// Default prefix of the retained settings, see `TopicsConfig`
+pub const SETTINGS_PREFIX: &str = "settings";
+
+/// Topic below the module topic the failures of the module are published on
+pub const ERRORS_TOPIC: &str = "errors";
+
+/// A module together with its handlers
+struct Registered<M> {
+    module: M,
+    handlers: Handlers<M>,
+    /// Number of messages the handlers of the module failed on
+    failures: AtomicU64,
+}
+
+/// A registered module, with the type of the module erased
+#[async_trait::async_trait]
+trait Entry: Send + Sync {
+    fn module(&self) -> &dyn ClientModule;
+
+    fn failures(&self) -> u64;
+
+    /// Whether there is a handler for the topic below the module topic or it is expected
+    fn handles(&self, sub_topic: &str) -> bool;
+
+    /// Decode the payload and run the handler of the topic below the module topic
+    async fn dispatch(&self, context: &HubContext, topic: &str, sub_topic: &str, payload: &str) -> Result<(), HandleError>;
+
+    fn has_events(&self) -> bool;
+
+    /// Forward the events the module handles to the sink, on the returned tasks
+    fn subscribe(&self, context: &HubContext, sink: EventSink) -> Vec<JoinHandle<()>>;
+
+    /// Run the handler of the event
+    async fn dispatch_event(&self, context: &HubContext, event: AnyEvent) -> Result<(), HandleError>;
+}
+
+#[async_trait::async_trait]
+impl<M: ClientModule + 'static> Entry for Registered<M> {
+    fn module(&self) -> &dyn ClientModule {
+        &self.module
+    }
+
+    fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    fn handles(&self, sub_topic: &str) -> bool {
+        // the failures are published by the manager
+        self.handlers.handles(sub_topic) || sub_topic == ERRORS_TOPIC
+    }
+
+    async fn dispatch(&self, context: &HubContext, topic: &str, sub_topic: &str, payload: &str) -> Result<(), HandleError> {
+        match self.handlers.call(&self.module, context, sub_topic, payload) {
+            Some(Ok(handler)) => handler.await.map_err(|error| {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                HandleError::Failed { module: self.module.name(), topic: topic.to_string(), error }
+            }),
+            Some(Err(error)) => Err(HandleError::Malformed { topic: topic.to_string(), error }),
+            None if self.handles(sub_topic) => Ok(()),
+            None => Err(HandleError::UnknownTopic(topic.to_string())),
+        }
+    }
+
+    fn has_events(&self) -> bool {
+        self.handlers.has_events()
+    }
+
+    fn subscribe(&self, context: &HubContext, sink: EventSink) -> Vec<JoinHandle<()>> {
+        self.handlers.subscribe(context.events(), sink)
+    }
+
+    async fn dispatch_event(&self, context: &HubContext, event: AnyEvent) -> Result<(), HandleError> {
+        // only events the module subscribed to are forwarded
+        let Some((name, handler)) = self.handlers.call_event(&self.module, context, event) else {
+            return Ok(());
+        };
+        handler.await.map_err(|error| {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            HandleError::Failed { module: self.module.name(), topic: name.to_string(), error }
+        })
+    }
+}
+
+/// Work for the task of a module
+enum Job {
+    /// Handled once the modules sharing the state are done with the messages queued before, see `after`
+    Message { topic: String, sub_topic: String, payload: String, after: Vec<oneshot::Receiver<()>> },
+    Event(AnyEvent),
+    /// Answered once the messages queued before are handled
+    Drain(oneshot::Sender<()>),
+}
+
+/// A registered module together with the queue of its messages
+struct Slot {
+    entry: Arc<dyn Entry>,
+    /// The task of the module is started with the first message or event
+    queue: OnceCell<UnboundedSender<Job>>,
+}
+
+/// Handle the messages and events of a module one after the other, in the order they were queued
+/// Malformed payloads and failed handlers are logged, the failures also published if enabled
+async fn work(entry: Arc<dyn Entry>, context: HubContext, publish_errors: bool, mut jobs: UnboundedReceiver<Job>) {
+    while let Some(job) = jobs.recv().await {
+        let result = match job {
+            Job::Message { topic, sub_topic, payload, after } => {
+                for drained in after {
+                    let _ = drained.await;
+                }
+                entry.dispatch(&context, &topic, &sub_topic, &payload).await
+            }
+            Job::Event(event) => entry.dispatch_event(&context, event).await,
+            Job::Drain(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        match result {
+            Ok(()) => {}
+            Err(HandleError::Failed { module, topic, error }) => {
+                let failures = entry.failures();
+                // with the sources, which tell custom errors apart
+                let error = error.report();
+                tracing::error!("Failed to handle a message: module '{}' failed on '{}': {} ({} failures so far)", module, topic, error, failures);
+                if publish_errors {
+                    let payload = serde_json::json!({
+                        "topic": topic,
+                        "error": error,
+                        "failures": failures,
+                    });
+                    context.publish(topic!(entry.module().topic(), ERRORS_TOPIC), QoS::AtLeastOnce, false, payload.to_string()).await;
+                }
+            }
+            Err(error) => tracing::warn!("Failed to handle a message: {}", error),
+        }
+    }
+}
 
 /// Core manager for handling modules and the settings for modules
 /// All modules should be registered with the manager
 /// The handle_message function should be called from the main loop
 pub struct ModuleManager {
-    modules: Vec<Box<dyn ClientModule>>,
-    configs: HashMap<String, String>,
+    context: HubContext,
+    modules: Vec<Slot>,
+    configs: HashMap<String, Setting>,
+    settings_prefix: String,
+    /// Whether the failures of the handlers are published on the errors topic of the module
+    publish_errors: bool,
+    /// The tasks forwarding the events from the bus to the modules, aborted on shutdown
+    forwarders: Mutex<Vec<JoinHandle<()>>>,
+}
+
+/// A manager with a context that only lives in memory
+impl Default for ModuleManager {
+    fn default() -> Self {
+        Self::new(HubContext::default())
+    }
 }
 
 impl ModuleManager {
-    /// Create a new ModuleManager
-    pub fn new() -> Self {
+    /// Create a new ModuleManager, the modules get the context passed
+    pub fn new(context: HubContext) -> Self {
         tracing::trace!("ModuleManager created");
 
         Self {
+            context,
             modules: Vec::new(),
             configs: HashMap::new(),
+            settings_prefix: SETTINGS_PREFIX.to_string(),
+            publish_errors: false,
+            forwarders: Mutex::new(Vec::new()),
         }
     }
 
+    /// The context the modules get passed
+    pub fn context(&self) -> &HubContext {
+        &self.context
+    }
+
+    /// Publish the failures of the handlers on the errors topic of their module
+    pub fn set_publish_errors(&mut self, publish: bool) {
+        self.publish_errors = publish;
+    }
+
+    /// Publish the settings below a different prefix
+    pub fn set_settings_prefix(&mut self, prefix: &str) {
+        self.settings_prefix = prefix.to_string();
+    }
+
+    /// The retained topic of a module setting
+    fn settings_topic(&self, topic: &str) -> String {
+        topic!(self.settings_prefix, topic)
+    }
+
+    /// The names of the registered modules, in the order of their registration
+    pub fn module_names(&self) -> Vec<String> {
+        self.modules.iter().map(|ele| ele.entry.module().name()).collect()
+    }
+
+    /// A setting of a registered module by its topic, like `home/sensor/check_duration`
+    pub fn setting(&self, topic: &str) -> Option<&Setting> {
+        self.configs.get(topic)
+    }
+
     /// Register a module with the manager
-    /// This will add the settings to the settings map
-    pub fn register_module(&mut self, module: impl ClientModule + 'static) {
+    /// This will add the settings to the settings map and the handlers of the module
+    pub fn register_module<M: ClientModule + 'static>(&mut self, module: M) {
         let name = module.name();
         // prefix the setting with the module topic
         let settings = module
             .settings()
-            .iter()
-            .map(|(k, v)| (format!("{}/{}", module.topic(), k), v.clone()))
-            .collect::<HashMap<String, String>>();
+            .into_iter()
+            .map(|setting| (topic!(module.topic(), setting.name), setting))
+            .collect::<HashMap<String, Setting>>();
 
         self.configs.extend(settings);
-        self.modules.push(Box::new(module));
+        let entry = Arc::new(Registered { module, handlers: M::handlers(), failures: AtomicU64::new(0) });
+        self.modules.push(Slot { entry, queue: OnceCell::new() });
         tracing::debug!("Registered module '{}'", name);
     }
 
-    /// Handle a message from the MQTT broker
-    /// Will filter the modules based on the topic and call the handle function of each matching module
-    pub async fn handle_message(&self, topic: &str, payload: &str) {
-        for ele in self.modules.iter().filter(|ele| topic.contains(&ele.topic())) {
-            tracing::debug!("Handling message for module '{}' on topic '{}'", ele.name(), topic);
-            ele.handle(topic, payload).await;
+    /// Hand a message from the MQTT broker to the modules below its topic
+    /// Returns right away, every module handles its messages on its own task in the order they arrived,
+    /// so a slow handler only holds up the messages of its module and of the modules sharing the state with it
+    /// Topics without a handler are logged and returned, malformed payloads and failed handlers are logged by the tasks
+    pub fn handle_message(&self, topic: &str, payload: &str) -> Vec<HandleError> {
+        let mut errors = Vec::new();
+        let mut matched = false;
+        for ele in self.modules.iter() {
+            let module = ele.entry.module();
+            let Some(sub_topic) = topic.strip_prefix(&module.topic()).and_then(|rest| rest.strip_prefix('/')) else {
+                continue;
+            };
+            matched = true;
+            if !ele.entry.handles(sub_topic) {
+                errors.push(HandleError::UnknownTopic(topic.to_string()));
+                continue;
+            }
+            tracing::debug!("Queueing message for module '{}' on topic '{}'", module.name(), topic);
+            let job = Job::Message {
+                topic: topic.to_string(),
+                sub_topic: sub_topic.to_string(),
+                payload: payload.to_string(),
+                after: self.after(ele),
+            };
+            let _ = self.queue(ele).send(job);
+        }
+        if !matched {
+            errors.push(HandleError::UnknownTopic(topic.to_string()));
         }
+
+        for error in &errors {
+            tracing::warn!("Failed to handle a message: {}", error);
+        }
+        errors
     }
 
-    /// Initialize the modules and settings
-    /// Subscribes to the topics of the modules and publishes the settings as retained messages
-    pub fn initialize(&self, client: &rumqttc::AsyncClient) {
-        for (topic, value) in &self.configs {
-            let new_topic = format!("settings/{}", topic);
-            let mut payload = value.to_string();
-            if payload.is_empty() {
-                continue;
+    /// The queue of a module, its task is started the first time
+    fn queue<'a>(&self, ele: &'a Slot) -> &'a UnboundedSender<Job> {
+        ele.queue.get_or_init(|| {
+            let (queue, jobs) = mpsc::unbounded_channel();
+            tokio::spawn(work(Arc::clone(&ele.entry), self.context.clone(), self.publish_errors, jobs));
+            queue
+        })
+    }
+
+    /// The messages queued before for the other modules sharing the state with the module
+    /// e.g. a sensor report has to be handled before the watering answers a valve requesting it
+    fn after(&self, ele: &Slot) -> Vec<oneshot::Receiver<()>> {
+        if !ele.entry.module().shares_state() {
+            return Vec::new();
+        }
+        self.modules
+            .iter()
+            .filter(|other| !std::ptr::eq(*other, ele) && other.entry.module().shares_state())
+            .filter_map(|other| other.queue.get())
+            .filter_map(|queue| {
+                let (done, drained) = oneshot::channel();
+                queue.send(Job::Drain(done)).ok().map(|_| drained)
+            })
+            .collect()
+    }
+
+    /// Wait until the modules handled the messages queued so far
+    pub async fn drain(&self) {
+        let mut pending = Vec::new();
+        for queue in self.modules.iter().filter_map(|ele| ele.queue.get()) {
+            let (done, drained) = oneshot::channel();
+            if queue.send(Job::Drain(done)).is_ok() {
+                pending.push(drained);
             }
-            // clean up the payload for strings
-            payload = payload.replace("\"", "");
+        }
+        for drained in pending {
+            let _ = drained.await;
+        }
+    }
 
-            // Publish the setting to the MQTT broker
-            let res = client.try_publish(
-                new_topic,
-                rumqttc::QoS::ExactlyOnce,
-                true,
-                payload.as_bytes()
-            );
+    /// The number of failed messages of every module that failed, by the name of the module
+    pub fn failures(&self) -> Vec<(String, u64)> {
+        self.modules
+            .iter()
+            .filter(|ele| ele.entry.failures() > 0)
+            .map(|ele| (ele.entry.module().name(), ele.entry.failures()))
+            .collect()
+    }
 
-            if res.is_ok() {
-                tracing::debug!(
-                    "Published settings for '{}' retain = true, value = '{}'",
-                    topic,
-                    value
-                );
-            } else {
-                tracing::error!(
-                    "Failed to publish settings for '{}' retain = true, value = '{}'",
-                    topic,
-                    value
-                );
+    /// Start all modules, once they are registered
+    /// The modules receive the events they handle from now on
+    pub async fn start(&self) {
+        for ele in self.modules.iter().filter(|ele| ele.entry.has_events()) {
+            // the subscriptions do not keep the task of the module alive
+            let queue = self.queue(ele).downgrade();
+            let sink: EventSink = Arc::new(move |event| queue.upgrade().is_some_and(|queue| queue.send(Job::Event(event)).is_ok()));
+            let forwarders = ele.entry.subscribe(&self.context, sink);
+            self.forwarders.lock().unwrap().extend(forwarders);
+        }
+        for ele in self.modules.iter() {
+            ele.entry.module().on_start(&self.context).await;
+        }
+    }
+
+    /// Tick all modules
+    pub async fn tick(&self) {
+        for ele in self.modules.iter() {
+            ele.entry.module().on_tick(&self.context).await;
+        }
+    }
+
+    /// Notify all modules about a change of the broker connection
+    pub async fn connection_changed(&self, state: ConnectionState) {
+        for ele in self.modules.iter() {
+            match state {
+                ConnectionState::Connected => ele.entry.module().on_connect(&self.context).await,
+                ConnectionState::Disconnected => ele.entry.module().on_disconnect(&self.context).await,
             }
         }
+    }
+
+    /// Shut all modules down, in the reverse order of their registration
+    /// The modules receive no more events, the messages and events queued before are handled first
+    pub async fn shutdown(&self) {
+        let forwarders = std::mem::take(&mut *self.forwarders.lock().unwrap());
+        for forwarder in forwarders {
+            forwarder.abort();
+            let _ = forwarder.await;
+        }
+        self.drain().await;
+        for ele in self.modules.iter().rev() {
+            ele.entry.module().on_shutdown(&self.context).await;
+        }
+        for (module, failures) in self.failures() {
+            tracing::warn!("Module '{}' failed to handle {} messages", module, failures);
+        }
+    }
+
+    /// The settings of all modules as retained messages, in the order of their topics
+    fn settings_messages(&self) -> Vec<Message> {
+        let mut configs = self.configs.iter().collect::<Vec<_>>();
+        configs.sort_by_key(|(topic, _)| *topic);
+
+        configs
+            .into_iter()
+            .filter(|(_, setting)| !setting.value.is_empty())
+            .map(|(topic, setting)| Message::new(self.settings_topic(topic), setting.qos, setting.retain, setting.value.as_str()))
+            .collect()
+    }
 
-        for module in &self.modules {
-            let res = client.try_subscribe(topic!(module.topic(), "#"), rumqttc::QoS::ExactlyOnce);
+    /// Initialize the modules and settings
+    /// Publishes the settings as retained messages in one batch and subscribes to the topics of the modules
+    /// Waits for room in the client, so it must not run on the task polling the event loop
+    /// Returns the outcome of every setting, the failed ones did not reach the client
+    pub async fn initialize(&self, publisher: &Publisher) -> Vec<Outcome> {
+        let outcomes = publisher.publish_batch(self.settings_messages()).await;
+        for outcome in &outcomes {
+            match &outcome.result {
+                Ok(()) => tracing::debug!("Published setting '{}' retain = {}", outcome.message.topic, outcome.message.retain),
+                Err(error) => tracing::error!("Failed to publish setting '{}': {}", outcome.message.topic, error),
+            }
+        }
+        let published = outcomes.iter().filter(|outcome| outcome.is_ok()).count();
+        tracing::info!("Published {}/{} settings", published, outcomes.len());
+
+        for module in self.modules.iter().map(|ele| ele.entry.module()) {
+            let res = publisher.subscribe(&topic!(module.topic(), "#"), QoS::ExactlyOnce).await;
 
             if res.is_ok() {
                 tracing::debug!("Subscribed to '{}'", module.topic());
@@ -89,17 +407,22 @@ impl ModuleManager {
                 tracing::error!("Failed to subscribe to '{}'", module.topic());
             }
         }
+
+        outcomes
     }
 }
 
 /// Tests for the ModuleManager
-/// The Initialize function is not tested as it requires a rumqttc::AsyncClient
-/// The handle_message function is not tested as it returns nothing
+/// The initialize function is tested against a broker
+/// The handle_message function is tested with a module recording what it handled
 #[cfg(test)]
 mod tests {
+    use std::sync::{ Arc, Mutex };
+
     use super::*;
+    use crate::ModuleError;
 
-    #[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+    #[derive(Debug, Default, serde::Deserialize, serde::Serialize, crate::ModuleSettings)]
     struct TestModule {
         config_data: String,
     }
@@ -109,33 +432,18 @@ mod tests {
         fn topic(&self) -> String {
             "test/topic".to_string()
         }
-
-        async fn handle(&self, _topic: &str, _payload: &str) {}
-
-        fn settings(&self) -> HashMap<String, String> {
-            let mut settings = HashMap::new();
-
-            let ser = serde_json::to_string(&self).unwrap();
-            let deser = serde_json
-                ::to_value(serde_json::from_str::<TestModule>(&ser).unwrap())
-                .unwrap();
-
-            settings.insert("config_data".to_string(), deser["config_data"].to_string());
-
-            settings
-        }
     }
 
     #[test]
     fn test_module_manager_new() {
-        let manager = ModuleManager::new();
+        let manager = ModuleManager::default();
         assert_eq!(manager.modules.len(), 0);
         assert_eq!(manager.configs.len(), 0);
     }
 
     #[test]
     fn test_register_module() {
-        let mut manager = ModuleManager::new();
+        let mut manager = ModuleManager::default();
         let module = TestModule::default();
         manager.register_module(module);
 
@@ -143,12 +451,450 @@ mod tests {
         assert_eq!(manager.configs.len(), 1);
     }
 
+    /// Module that records the connection changes it was notified about
+    #[derive(Default, crate::ModuleSettings)]
+    struct ConnectionModule {
+        #[setting(skip)]
+        changes: Arc<Mutex<Vec<ConnectionState>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ClientModule for ConnectionModule {
+        fn topic(&self) -> String {
+            "test/connection".to_string()
+        }
+
+        async fn on_connect(&self, _context: &HubContext) {
+            self.changes.lock().unwrap().push(ConnectionState::Connected);
+        }
+
+        async fn on_disconnect(&self, _context: &HubContext) {
+            self.changes.lock().unwrap().push(ConnectionState::Disconnected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_changed_notifies_modules() {
+        let mut manager = ModuleManager::default();
+        let module = ConnectionModule::default();
+        let changes = Arc::clone(&module.changes);
+        manager.register_module(module);
+
+        manager.connection_changed(ConnectionState::Connected).await;
+        manager.connection_changed(ConnectionState::Disconnected).await;
+
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![ConnectionState::Connected, ConnectionState::Disconnected]
+        );
+    }
+
+    /// Module that records the lifecycle hooks it was called with
+    #[derive(crate::ModuleSettings)]
+    struct LifecycleModule {
+        #[setting(skip)]
+        name: &'static str,
+        #[setting(skip)]
+        hooks: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl LifecycleModule {
+        fn record(&self, hook: &str) {
+            self.hooks.lock().unwrap().push(format!("{} {}", self.name, hook));
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ClientModule for LifecycleModule {
+        fn topic(&self) -> String {
+            topic!("test", self.name)
+        }
+
+        async fn on_start(&self, _context: &HubContext) {
+            self.record("start");
+        }
+
+        async fn on_tick(&self, _context: &HubContext) {
+            self.record("tick");
+        }
+
+        async fn on_shutdown(&self, _context: &HubContext) {
+            self.record("shutdown");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_hooks() {
+        let hooks = Arc::new(Mutex::new(Vec::new()));
+        let mut manager = ModuleManager::default();
+        manager.register_module(LifecycleModule { name: "first", hooks: Arc::clone(&hooks) });
+        manager.register_module(LifecycleModule { name: "second", hooks: Arc::clone(&hooks) });
+
+        manager.start().await;
+        manager.tick().await;
+        manager.shutdown().await;
+
+        assert_eq!(
+            *hooks.lock().unwrap(),
+            vec!["first start", "second start", "first tick", "second tick", "second shutdown", "first shutdown"]
+        );
+    }
+
     #[test]
     fn test_setting_prefix_correctly(){
-        let mut manager = ModuleManager::new();
+        let mut manager = ModuleManager::default();
         let module = TestModule::default();
         manager.register_module(module);
 
         assert!(manager.configs.contains_key("test/topic/config_data"));
     }
+
+    #[test]
+    fn test_settings_topic_follows_prefix() {
+        let mut manager = ModuleManager::default();
+        assert_eq!(manager.settings_topic("test/topic/config_data"), "settings/test/topic/config_data");
+
+        manager.set_settings_prefix("garden-a/settings");
+        assert_eq!(manager.settings_topic("test/topic/config_data"), "garden-a/settings/test/topic/config_data");
+    }
+
+    /// Module that records the valve durations it was asked to open for
+    #[derive(Default, crate::ModuleSettings)]
+    struct RecordingModule {
+        #[setting(skip)]
+        opened: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl RecordingModule {
+        async fn open(&self, _context: &HubContext, seconds: u64) -> Result<(), ModuleError> {
+            if seconds == 0 {
+                return Err(ModuleError::Publish("test/valve/open/response".to_string()));
+            }
+            self.opened.lock().unwrap().push(seconds);
+            Ok(())
+        }
+
+        /// The schedule is JSON inside the payload, custom failures are reported with `?`
+        async fn schedule(&self, _context: &HubContext, schedule: String) -> Result<(), ModuleError> {
+            let seconds = serde_json::from_str::<Vec<u64>>(&schedule)?;
+            self.opened.lock().unwrap().extend(seconds);
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ClientModule for RecordingModule {
+        fn topic(&self) -> String {
+            "test/valve".to_string()
+        }
+
+        fn handlers() -> Handlers<Self> {
+            Handlers::new().on("open", Self::open).on("schedule", Self::schedule).ignore("open/response")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_message_reports_errors() {
+        let mut manager = ModuleManager::default();
+        let module = RecordingModule::default();
+        let opened = Arc::clone(&module.opened);
+        manager.register_module(module);
+
+        assert!(manager.handle_message("test/valve/open", "30").is_empty());
+        assert!(manager.handle_message("test/valve/open/response", "true").is_empty());
+        // malformed payloads are only noticed by the task of the module
+        assert!(manager.handle_message("test/valve/open", "soon").is_empty());
+        let errors = manager.handle_message("test/valve/close", "");
+        assert!(matches!(errors.as_slice(), [HandleError::UnknownTopic(topic)] if topic == "test/valve/close"));
+        // not below the topic of any module
+        let errors = manager.handle_message("test/valveless/open", "30");
+        assert!(matches!(errors.as_slice(), [HandleError::UnknownTopic(_)]));
+
+        manager.drain().await;
+        assert_eq!(*opened.lock().unwrap(), vec![30]);
+        assert!(manager.failures().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_handlers_are_counted_and_published() {
+        let mut manager = ModuleManager::default();
+        manager.register_module(RecordingModule::default());
+        manager.set_publish_errors(true);
+
+        manager.handle_message("test/valve/open", "0");
+        manager.handle_message("test/valve/open", "0");
+        manager.drain().await;
+        assert_eq!(manager.failures(), vec![("valve".to_string(), 2)]);
+
+        let outbox = manager.context().outbox().lock().await;
+        let messages = outbox.messages().collect::<Vec<_>>();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].topic, "test/valve/errors");
+        assert!(!messages[1].retain);
+        let payload = serde_json::from_slice::<serde_json::Value>(&messages[1].payload).unwrap();
+        assert_eq!(payload["topic"], "test/valve/open");
+        assert_eq!(payload["failures"], 2);
+        assert_eq!(payload["error"], "failed to publish on 'test/valve/open/response'");
+
+        // the published failures are not handled as messages again
+        assert!(manager.handle_message("test/valve/errors", &payload.to_string()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_custom_failures_are_published() {
+        let mut manager = ModuleManager::default();
+        manager.register_module(RecordingModule::default());
+        manager.set_publish_errors(true);
+
+        manager.handle_message("test/valve/schedule", "tomorrow");
+        manager.drain().await;
+        assert_eq!(manager.failures(), vec![("valve".to_string(), 1)]);
+
+        let outbox = manager.context().outbox().lock().await;
+        let message = outbox.messages().next().unwrap();
+        let payload = serde_json::from_slice::<serde_json::Value>(&message.payload).unwrap();
+        assert_eq!(payload["topic"], "test/valve/schedule");
+        assert_eq!(payload["error"], "expected ident at line 1 column 2");
+    }
+
+    /// Module that waits for the gate before every message, if it has one
+    #[derive(crate::ModuleSettings)]
+    struct SlowModule {
+        #[setting(skip)]
+        name: &'static str,
+        #[setting(skip)]
+        gate: Option<Arc<tokio::sync::Semaphore>>,
+        #[setting(skip)]
+        handled: tokio::sync::mpsc::UnboundedSender<String>,
+    }
+
+    impl SlowModule {
+        async fn work(&self, _context: &HubContext, job: u64) -> Result<(), ModuleError> {
+            if let Some(gate) = &self.gate {
+                gate.acquire().await.unwrap().forget();
+            }
+            let _ = self.handled.send(format!("{} {}", self.name, job));
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ClientModule for SlowModule {
+        fn topic(&self) -> String {
+            topic!("test", self.name)
+        }
+
+        fn handlers() -> Handlers<Self> {
+            Handlers::new().on("work", Self::work)
+        }
+    }
+
+    /// The next message a module handled, the test fails instead of hanging if there is none
+    async fn next_handled(handled: &mut tokio::sync::mpsc::UnboundedReceiver<String>) -> String {
+        tokio::time::timeout(std::time::Duration::from_secs(5), handled.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_slow_module_does_not_hold_up_the_others() {
+        let (sender, mut handled) = tokio::sync::mpsc::unbounded_channel();
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let mut manager = ModuleManager::default();
+        manager.register_module(SlowModule { name: "slow", gate: Some(Arc::clone(&gate)), handled: sender.clone() });
+        manager.register_module(SlowModule { name: "fast", gate: None, handled: sender });
+
+        for job in 0..3 {
+            manager.handle_message("test/slow/work", &job.to_string());
+            manager.handle_message("test/fast/work", &job.to_string());
+        }
+        // the slow module waits for the gate, the fast one is done meanwhile
+        for job in 0..3 {
+            assert_eq!(next_handled(&mut handled).await, format!("fast {}", job));
+        }
+
+        // the messages of every module are handled in the order they arrived
+        gate.add_permits(3);
+        manager.drain().await;
+        for job in 0..3 {
+            assert_eq!(next_handled(&mut handled).await, format!("slow {}", job));
+        }
+    }
+
+    /// Module that writes the watering state of the hub or reads it
+    #[derive(Default, crate::ModuleSettings)]
+    struct StateModule {
+        #[setting(skip)]
+        name: &'static str,
+        #[setting(skip)]
+        read: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl StateModule {
+        async fn write(&self, context: &HubContext, needed: bool) -> Result<(), ModuleError> {
+            // let the other modules run in between
+            tokio::task::yield_now().await;
+            context.state().lock().await.watering_needed = needed;
+            Ok(())
+        }
+
+        async fn read(&self, context: &HubContext, _request: serde::de::IgnoredAny) -> Result<(), ModuleError> {
+            let needed = context.state().lock().await.watering_needed;
+            self.read.lock().unwrap().push(needed);
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ClientModule for StateModule {
+        fn topic(&self) -> String {
+            topic!("test", self.name)
+        }
+
+        fn handlers() -> Handlers<Self> {
+            Handlers::new().on("write", Self::write).on("read", Self::read)
+        }
+
+        fn shares_state(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_modules_sharing_the_state_keep_the_order() {
+        let read = Arc::new(Mutex::new(Vec::new()));
+        let mut manager = ModuleManager::default();
+        manager.register_module(StateModule { name: "sensor", read: Arc::clone(&read) });
+        manager.register_module(StateModule { name: "valve", read: Arc::clone(&read) });
+
+        manager.handle_message("test/valve/read", "");
+        manager.handle_message("test/sensor/write", "true");
+        manager.handle_message("test/valve/read", "");
+        manager.handle_message("test/sensor/write", "false");
+        manager.handle_message("test/valve/read", "");
+        manager.drain().await;
+        assert_eq!(*read.lock().unwrap(), vec![false, true, false]);
+    }
+
+    #[derive(Clone, Debug)]
+    struct Rain(u64);
+
+    impl crate::Event for Rain {}
+
+    /// Module that reacts to the events of the other modules
+    #[derive(Default, serde::Deserialize, serde::Serialize, crate::ModuleSettings)]
+    struct RainModule {
+        #[serde(skip)]
+        rain: Arc<Mutex<Vec<u64>>>,
+        /// Told once two events were handled
+        #[serde(skip)]
+        done: Mutex<Option<oneshot::Sender<()>>>,
+    }
+
+    impl RainModule {
+        async fn on_rain(&self, _context: &HubContext, rain: Rain) -> Result<(), ModuleError> {
+            let mut handled = self.rain.lock().unwrap();
+            handled.push(rain.0);
+            if handled.len() == 2 {
+                if let Some(done) = self.done.lock().unwrap().take() {
+                    let _ = done.send(());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ClientModule for RainModule {
+        fn topic(&self) -> String {
+            "test/rain".to_string()
+        }
+
+        fn handlers() -> Handlers<Self> {
+            Handlers::new().event(Self::on_rain)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_are_handled_on_the_module_task() {
+        let rain = Arc::new(Mutex::new(Vec::new()));
+        let (done, handled) = oneshot::channel();
+        let mut manager = ModuleManager::default();
+        manager.register_module(RainModule { rain: Arc::clone(&rain), done: Mutex::new(Some(done)) });
+        manager.register_module(TestModule::default());
+
+        // nobody listens before the modules are started
+        assert_eq!(manager.context().events().publish(Rain(5)), 0);
+        manager.start().await;
+        assert_eq!(manager.context().events().publish(Rain(10)), 1);
+        assert_eq!(manager.context().events().publish(Rain(20)), 1);
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), handled).await.unwrap().unwrap();
+        manager.drain().await;
+        assert_eq!(*rain.lock().unwrap(), vec![10, 20]);
+
+        // the events are no longer forwarded after the shutdown
+        manager.shutdown().await;
+        assert_eq!(manager.context().events().publish(Rain(30)), 0);
+    }
+
+    /// Module with many settings, more than the request channel of a client holds
+    struct ManySettingsModule(usize);
+
+    #[async_trait::async_trait]
+    impl ClientModule for ManySettingsModule {
+        fn topic(&self) -> String {
+            "test/many".to_string()
+        }
+    }
+
+    /// The settings are not fields, so they are implemented by hand
+    impl crate::ModuleSettings for ManySettingsModule {
+        fn settings(&self) -> Vec<Setting> {
+            (0..self.0)
+                .map(|index| Setting::new(&format!("setting_{:03}", index), &index.into(), QoS::ExactlyOnce, true))
+                .collect()
+        }
+
+        fn update_setting(&mut self, name: &str, _payload: &str) -> std::io::Result<()> {
+            Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("unknown setting '{}'", name)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_initialize_publishes_hundreds_of_settings() {
+        use rumqttc::{ AsyncClient, Event, Incoming, MqttOptions };
+
+        const SETTINGS: usize = 500;
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut broker_config = mqttd::default_config();
+        broker_config.v5 = None;
+        mqttd::Overrides {
+            v4_listen: Some(format!("127.0.0.1:{}", port).parse().unwrap()),
+            ..Default::default()
+        }
+            .apply(&mut broker_config)
+            .unwrap();
+        let broker = mqttd::start(broker_config, mqttd::default_extensions(), None).unwrap();
+        broker.ready(std::time::Duration::from_secs(5)).await.unwrap();
+
+        let mut manager = ModuleManager::default();
+        manager.register_module(ManySettingsModule(SETTINGS));
+        let (client, mut eventloop) = AsyncClient::new(MqttOptions::new("manager-test", "127.0.0.1", port), 10);
+        let (acknowledged, mut acknowledgements) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(event) = eventloop.poll().await {
+                if let Event::Incoming(Incoming::PubComp(_)) = event {
+                    let _ = acknowledged.send(());
+                }
+            }
+        });
+
+        let outcomes = manager.initialize(&Publisher::new(client, Default::default())).await;
+        assert_eq!(outcomes.len(), SETTINGS);
+        assert!(outcomes.iter().all(|outcome| outcome.is_ok()));
+        assert_eq!(outcomes[42].message.topic, "settings/test/many/setting_042");
+        // every setting was completed by the broker
+        for _ in 0..SETTINGS {
+            tokio::time::timeout(std::time::Duration::from_secs(10), acknowledgements.recv()).await.unwrap();
+        }
+    }
 }
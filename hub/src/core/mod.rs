@@ -1,7 +1,18 @@
+mod context;
+mod error;
+mod events;
+mod handlers;
 mod manager;
+mod publisher;
 pub mod serde;
 pub mod traits;
 pub mod macros;
 
-pub use manager::ModuleManager;
-pub use traits::ClientModule;
+pub use context::{ Clock, HubContext, SystemClock };
+pub use error::ModuleError;
+pub use events::{ Event, EventBus };
+pub use handlers::{ AnyEvent, EventSink, HandleError, Handler, HandlerFuture, Handlers };
+pub use manager::{ ModuleManager, SETTINGS_PREFIX };
+pub use publisher::{ Outcome, Publisher };
+pub use traits::{ ClientModule, ModuleSettings, Setting };
+pub use hub_derive::ModuleSettings;
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use rumqttc::{ AsyncClient, ClientError, QoS };
+
+use crate::outbox::{ Deliveries, Message };
+
+/// Publishes for the modules
+/// Unlike `try_publish` it waits for room in the request channel of the client instead of failing,
+/// so it must not be awaited on the task that polls the event loop
+/// The publications are noted in the deliveries of the outbox, so its acknowledgements are told apart
+#[derive(Clone, Debug)]
+pub struct Publisher {
+    client: AsyncClient,
+    deliveries: Arc<Deliveries>,
+}
+
+/// What became of one publication
+#[derive(Debug)]
+pub struct Outcome {
+    pub message: Message,
+    /// Only fails if the event loop is gone, the message did not reach the client then
+    pub result: Result<(), ClientError>,
+}
+
+impl Outcome {
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+impl Publisher {
+    pub fn new(client: AsyncClient, deliveries: Arc<Deliveries>) -> Self {
+        Self { client, deliveries }
+    }
+
+    pub fn client(&self) -> &AsyncClient {
+        &self.client
+    }
+
+    /// Publish one message once the client has room for it
+    pub async fn publish(&self, message: Message) -> Outcome {
+        let qos = rumqttc::qos(message.qos).unwrap_or(QoS::AtLeastOnce);
+        let _sending = self.deliveries.sending().await;
+        self.deliveries.handed_over(None);
+        let result = self.client.publish(&message.topic, qos, message.retain, message.payload.clone()).await;
+        if result.is_err() {
+            self.deliveries.withdrawn();
+        }
+        Outcome { message, result }
+    }
+
+    /// Publish the messages in order, each waiting for room in the client
+    /// Returns the outcome of every message in the same order
+    pub async fn publish_batch(&self, messages: impl IntoIterator<Item = Message>) -> Vec<Outcome> {
+        let mut outcomes = Vec::new();
+        for message in messages {
+            outcomes.push(self.publish(message).await);
+        }
+        outcomes
+    }
+
+    /// Subscribe once the client has room for it
+    pub async fn subscribe(&self, filter: &str, qos: QoS) -> Result<(), ClientError> {
+        self.client.subscribe(filter, qos).await
+    }
+}
@@ -3,7 +3,7 @@ use std::fmt;
 use chrono::{NaiveTime, Timelike};
 use serde::{
     de::{self, Visitor},
-    Deserializer, Serializer,
+    Deserialize, Deserializer, Serializer,
 };
 
 // Custom function to serialize NaiveTime to "HH:MM" format
@@ -44,6 +44,47 @@ where
     deserializer.deserialize_str(NaiveTimeVisitor)
 }
 
+/// A bool that is also read from the strings "true" and "false" in any case, like "TRUE" from a device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LenientBool(pub bool);
+
+impl<'de> Deserialize<'de> for LenientBool {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct LenientBoolVisitor;
+
+        impl<'de> Visitor<'de> for LenientBoolVisitor {
+            type Value = LenientBool;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a bool or the string true or false")
+            }
+
+            fn visit_bool<E>(self, value: bool) -> Result<LenientBool, E>
+            where
+                E: de::Error,
+            {
+                Ok(LenientBool(value))
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<LenientBool, E>
+            where
+                E: de::Error,
+            {
+                match value.trim() {
+                    value if value.eq_ignore_ascii_case("true") => Ok(LenientBool(true)),
+                    value if value.eq_ignore_ascii_case("false") => Ok(LenientBool(false)),
+                    _ => Err(de::Error::invalid_value(de::Unexpected::Str(value), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(LenientBoolVisitor)
+    }
+}
+
 // Tests for the custom serialization and deserialization functions
 #[cfg(test)]
 mod tests {
@@ -75,4 +116,12 @@ mod tests {
         let testing = serde_json::from_str::<Testing>(r#"{"time":"12:34"}"#).unwrap();
         assert_eq!(testing.time, NaiveTime::from_hms_opt(12, 34, 0).unwrap());
     }
+
+    #[test]
+    fn test_deserialize_lenient_bool() {
+        assert_eq!(serde_json::from_str::<LenientBool>("true").unwrap(), LenientBool(true));
+        assert_eq!(serde_json::from_str::<LenientBool>(r#""TRUE""#).unwrap(), LenientBool(true));
+        assert_eq!(serde_json::from_str::<LenientBool>(r#""False""#).unwrap(), LenientBool(false));
+        assert!(serde_json::from_str::<LenientBool>(r#""yes""#).is_err());
+    }
 }
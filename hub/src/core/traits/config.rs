@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::{ io, path::Path };
 
 /// A trait for loading and saving configuration files
 pub trait ConfigFile<P: AsRef<Path>> {
@@ -6,20 +6,36 @@ pub trait ConfigFile<P: AsRef<Path>> {
 
     const PATH: P;
 
-    /// Load settings from the file or return default settings
-    fn load() -> Self::Config {
-        std::fs::read_to_string(Self::PATH.as_ref())
-            .map(|s| serde_json::from_str::<Self::Config>(&s).unwrap())
-            .unwrap_or_default()
+    /// Load settings from the file or return default settings if there is none
+    fn load() -> io::Result<Self::Config> {
+        Self::load_from(Self::PATH)
+    }
+
+    /// Load settings from a different file or return default settings if there is none
+    /// A file that can not be read or parsed is an error
+    fn load_from(path: impl AsRef<Path>) -> io::Result<Self::Config> {
+        match std::fs::read_to_string(path.as_ref()) {
+            Ok(s) => serde_json::from_str::<Self::Config>(&s).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::Config::default()),
+            Err(error) => Err(error),
+        }
     }
 
     /// Save settings to the file
-    fn save(&self)
+    fn save(&self) -> io::Result<()>
+    where
+        Self: serde::Serialize,
+    {
+        self.save_to(Self::PATH)
+    }
+
+    /// Save settings to a different file
+    fn save_to(&self, path: impl AsRef<Path>) -> io::Result<()>
     where
         Self: serde::Serialize,
     {
-        let s = serde_json::to_string(self).unwrap();
-        std::fs::write(Self::PATH.as_ref(), s).unwrap();
+        let s = serde_json::to_string(self)?;
+        std::fs::write(path.as_ref(), s)
     }
 }
 
@@ -44,10 +60,25 @@ mod tests {
 
     #[test]
     fn test_config_file_load() {
-        let config = TestConfig::load();
+        let config = TestConfig::load().unwrap();
         assert_eq!(config.test, "");
     }
 
+    #[test]
+    fn test_config_file_load_from_missing() {
+        let config = TestConfig::load_from("missing.json").unwrap();
+        assert_eq!(config.test, "");
+    }
+
+    #[test]
+    fn test_config_file_load_from_invalid() {
+        let path = std::env::temp_dir().join("hub-test-invalid-config.json");
+        std::fs::write(&path, "{\"test\": 1}").unwrap();
+        assert_eq!(TestConfig::load_from(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
     /// This will also test the load from a file
     #[test]
     fn test_config_file_save() {
@@ -55,9 +86,9 @@ mod tests {
         assert_eq!(config.test, "");
 
         config.test = "test".to_string();
-        config.save();
+        config.save().unwrap();
 
-        let config_loaded = TestConfig::load();
+        let config_loaded = TestConfig::load().unwrap();
         assert_eq!(config_loaded.test, "test");
 
         // Clean up test file
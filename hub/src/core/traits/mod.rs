@@ -1,5 +1,7 @@
 mod config;
 mod module;
+mod settings;
 
 pub use config::ConfigFile;
 pub use module::ClientModule;
+pub use settings::{ update_field, ModuleSettings, Setting };
@@ -1,21 +1,45 @@
-use std::collections::HashMap;
+use crate::{ Handlers, HubContext, ModuleSettings };
 
 /// A trait for modules that can be added to the Hub
+/// The settings come from `ModuleSettings`, usually derived
 #[async_trait::async_trait]
-pub trait ClientModule: Send + Sync {
+pub trait ClientModule: ModuleSettings + Send + Sync {
     /// The name of the module (last part of the topic by default)
     fn name(&self) -> String {
-        self.topic().clone().split('/').last().unwrap_or_default().to_string()
+        self.topic().split('/').next_back().unwrap_or_default().to_string()
     }
 
     /// The topic the module is interested in
     fn topic(&self) -> String;
 
-    /// The handlers for the module
-    async fn handle(&self, topic: &str, payload: &str);
+    /// The handlers of the topics below the module topic
+    /// Messages on other topics below the module topic are reported by the `ModuleManager`
+    fn handlers() -> Handlers<Self>
+        where Self: Sized
+    {
+        Handlers::default()
+    }
+
+    /// Whether the handlers read or change the state of the hub
+    /// The messages of the modules sharing the state are handled in the order they arrived, across those modules
+    fn shares_state(&self) -> bool {
+        false
+    }
+
+    /// Called once after all modules are registered, before the client connects
+    async fn on_start(&self, _context: &HubContext) {}
 
-    /// The settings for the module
-    fn settings(&self) -> HashMap<String, String>;
+    /// Called every tick interval of the modules, for timers
+    async fn on_tick(&self, _context: &HubContext) {}
+
+    /// Called whenever the broker acknowledged the connection, after the settings were published
+    async fn on_connect(&self, _context: &HubContext) {}
+
+    /// Called whenever the connection to the MQTT broker is lost
+    async fn on_disconnect(&self, _context: &HubContext) {}
+
+    /// Called during the graceful shutdown, before the state and settings are saved
+    async fn on_shutdown(&self, _context: &HubContext) {}
 }
 
 /// Test the ClientModule trait with a simple module
@@ -23,7 +47,7 @@ pub trait ClientModule: Send + Sync {
 mod simple_module_tests {
     use super::*;
 
-    #[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+    #[derive(Debug, Default, serde::Deserialize, serde::Serialize, crate::ModuleSettings)]
     struct SimpleTestModule;
 
     #[async_trait::async_trait]
@@ -31,12 +55,6 @@ mod simple_module_tests {
         fn topic(&self) -> String {
             "test/topic".to_string()
         }
-
-        async fn handle(&self, _topic: &str, _payload: &str) {}
-
-        fn settings(&self) -> HashMap<String, String> {
-            HashMap::new()
-        }
     }
 
     #[test]
@@ -53,7 +71,7 @@ mod simple_module_tests {
 mod module_tests {
     use super::*;
 
-    #[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+    #[derive(Debug, Default, serde::Deserialize, serde::Serialize, crate::ModuleSettings)]
     struct TestModule {
         config_data: String,
     }
@@ -63,22 +81,6 @@ mod module_tests {
         fn topic(&self) -> String {
             "test/topic".to_string()
         }
-
-        async fn handle(&self, _topic: &str, _payload: &str) {}
-
-        fn settings(&self) -> HashMap<String, String> {
-            let mut settings = HashMap::new();
-
-            let ser = serde_json::to_string(&self).unwrap();
-            let deser = serde_json
-                ::to_value(serde_json::from_str::<TestModule>(&ser).unwrap())
-                .unwrap();
-
-            for (key, value) in deser.as_object().unwrap() {
-                settings.insert(key.clone(), value.to_string());
-            }
-            settings
-        }
     }
 
     #[test]
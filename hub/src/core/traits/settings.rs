@@ -0,0 +1,149 @@
+use std::io;
+
+use rumqttc::QoS;
+use serde::{ de::DeserializeOwned, Serialize };
+
+/// The settings of a module, usually derived with `#[derive(ModuleSettings)]`
+pub trait ModuleSettings {
+    /// The settings, published as retained messages below the module topic
+    fn settings(&self) -> Vec<Setting>;
+
+    /// Update a setting from the payload of its topic
+    fn update_setting(&mut self, name: &str, payload: &str) -> io::Result<()>;
+}
+
+/// One setting of a module and how it is published
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Setting {
+    pub name: String,
+    /// The payload, strings without quotes
+    pub value: String,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+impl Setting {
+    pub fn new(name: &str, value: &serde_json::Value, qos: QoS, retain: bool) -> Self {
+        let value = match value {
+            serde_json::Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        Self { name: name.to_string(), value, qos, retain }
+    }
+}
+
+/// Parse the payload of a setting into its field
+/// The module is deserialized with the new value, so the serde attributes of the field apply,
+/// and the field is taken from it. Payloads that are no valid JSON are read as strings.
+pub fn update_field<M, T>(module: &M, name: &str, payload: &str, field: impl FnOnce(M) -> T) -> io::Result<T>
+    where M: Serialize + DeserializeOwned
+{
+    let mut value = serde_json::to_value(module).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    let object = value
+        .as_object_mut()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the module is not serialized as an object"))?;
+
+    let mut candidates = Vec::new();
+    if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(payload) {
+        candidates.push(parsed);
+    }
+    candidates.push(serde_json::Value::String(payload.to_string()));
+
+    let mut last_error = None;
+    for candidate in candidates {
+        object.insert(name.to_string(), candidate);
+        match serde_json::from_value::<M>(serde_json::Value::Object(object.clone())) {
+            Ok(updated) => {
+                return Ok(field(updated));
+            }
+            Err(error) => {
+                last_error = Some(error);
+            }
+        }
+    }
+
+    Err(
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid value '{}' for setting '{}': {}", payload, name, last_error.unwrap())
+        )
+    )
+}
+
+/// Test the derived settings of a module
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+    use serde::Deserialize;
+
+    use crate::ModuleSettings;
+
+    use super::*;
+
+    #[derive(ModuleSettings, Serialize, Deserialize)]
+    #[settings(topic = "garden")]
+    struct GardenModule {
+        #[serde(
+            serialize_with = "crate::serde::serialize_naive_time",
+            deserialize_with = "crate::serde::deserialize_naive_time"
+        )]
+        check_time: NaiveTime,
+        #[setting(qos = 1, retain = false)]
+        duration: u64,
+        #[serde(rename = "label")]
+        name: String,
+        #[setting(skip)]
+        internal: bool,
+        #[serde(skip)]
+        prefix: String,
+    }
+
+    fn module() -> GardenModule {
+        GardenModule {
+            check_time: NaiveTime::from_hms_opt(3, 0, 0).unwrap(),
+            duration: 30,
+            name: "Backyard".to_string(),
+            internal: true,
+            prefix: "home".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_derived_settings() {
+        let settings = module().settings();
+        assert_eq!(GardenModule::TOPIC, "garden");
+        assert_eq!(
+            settings,
+            vec![
+                Setting { name: "check_time".to_string(), value: "03:00".to_string(), qos: QoS::ExactlyOnce, retain: true },
+                Setting { name: "duration".to_string(), value: "30".to_string(), qos: QoS::AtLeastOnce, retain: false },
+                Setting { name: "label".to_string(), value: "Backyard".to_string(), qos: QoS::ExactlyOnce, retain: true }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_typed_update() {
+        let mut module = module();
+        module.update_setting("check_time", "04:30").unwrap();
+        module.update_setting("duration", "45").unwrap();
+        // a number is still a valid name
+        module.update_setting("label", "42").unwrap();
+
+        assert_eq!(module.check_time, NaiveTime::from_hms_opt(4, 30, 0).unwrap());
+        assert_eq!(module.duration, 45);
+        assert_eq!(module.name, "42");
+        // the other fields are left alone
+        assert!(module.internal);
+        assert_eq!(module.prefix, "home");
+    }
+
+    #[test]
+    fn test_invalid_update() {
+        let mut module = module();
+        assert_eq!(module.update_setting("duration", "soon").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(module.update_setting("check_time", "25:00").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(module.update_setting("internal", "false").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(module.duration, 30);
+    }
+}
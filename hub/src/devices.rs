@@ -0,0 +1,97 @@
+use std::{ io, path::{ Path, PathBuf } };
+
+use clap::Subcommand;
+use mqttd::{ acl::Role, auth::{ self, Credentials } };
+
+/// Manage the devices allowed to connect to the broker
+#[derive(Subcommand)]
+pub enum DevicesCommand {
+    /// Register a device or change its password and role
+    Add {
+        /// Username the device connects with
+        name: String,
+        /// Role deciding which topics the device may use
+        #[arg(long, value_enum)]
+        role: Role,
+        /// Password of the device, a random one is generated and printed if not given
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Remove a device, it can no longer connect
+    Remove {
+        /// Username of the device
+        name: String,
+    },
+    /// List the registered devices
+    List,
+}
+
+/// The credential store to manage, an existing one is preferred over creating a new one
+fn store_path(path: Option<&Path>) -> PathBuf {
+    auth::locate_credentials(path, None).unwrap_or_else(auth::default_credentials_path)
+}
+
+/// Run the command against the credential store
+pub fn run(command: DevicesCommand, path: Option<&Path>) -> io::Result<()> {
+    let path = store_path(path);
+    let mut credentials = Credentials::load(&path)?;
+
+    match command {
+        DevicesCommand::Add { name, role, password } => {
+            let generated = password.is_none();
+            let password = password.unwrap_or_else(auth::generate_password);
+            credentials.set(&name, &password, role);
+            credentials.save(&path)?;
+
+            println!("Device '{}' with role {:?} added to '{}'", name, role, path.display());
+            if generated {
+                println!("Password: {}", password);
+            }
+        }
+        DevicesCommand::Remove { name } => {
+            if !credentials.remove(&name) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("no device '{}' in '{}'", name, path.display())));
+            }
+            credentials.save(&path)?;
+            println!("Device '{}' removed from '{}'", name, path.display());
+        }
+        DevicesCommand::List => {
+            for device in credentials.devices() {
+                match credentials.role(device) {
+                    Some(role) => println!("{} ({:?})", device, role),
+                    None => println!("{} (no role)", device),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_and_remove_device() {
+        let path = std::env::temp_dir().join("hub-test-devices.toml");
+        let _ = std::fs::remove_file(&path);
+
+        let add = DevicesCommand::Add {
+            name: "sensor-1".to_string(),
+            role: Role::Sensor,
+            password: Some("secret".to_string()),
+        };
+        run(add, Some(&path)).unwrap();
+        let credentials = Credentials::load(&path).unwrap();
+        assert!(credentials.authenticate("sensor-1", "secret").is_ok());
+        assert_eq!(credentials.role("sensor-1"), Some(Role::Sensor));
+
+        run(DevicesCommand::Remove { name: "sensor-1".to_string() }, Some(&path)).unwrap();
+        assert_eq!(Credentials::load(&path).unwrap().devices().count(), 0);
+
+        let missing = run(DevicesCommand::Remove { name: "sensor-1".to_string() }, Some(&path));
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
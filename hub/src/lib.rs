@@ -0,0 +1,30 @@
+//! TerraTap hub, connects the sensors and valves over MQTT
+//!
+//! The hub can be embedded with the `HubBuilder`, together with custom `ClientModule`s.
+
+// the derive macros refer to the hub by its name, also inside of the hub
+extern crate self as hub;
+
+mod builder;
+mod core;
+pub use core::*;
+
+pub mod bridge;
+pub mod config;
+pub mod modules;
+pub mod mqttc;
+pub mod outbox;
+mod settings;
+mod state;
+
+pub use builder::{ Hub, HubBuilder };
+pub use settings::Settings;
+pub use state::State;
+pub use traits::ConfigFile;
+
+/// Used by the derive macros, so the crates of the modules do not need these dependencies
+#[doc(hidden)]
+pub mod __private {
+    pub use rumqttc::QoS;
+    pub use serde_json;
+}
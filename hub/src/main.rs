@@ -1,63 +1,53 @@
-use modules::{SensorModule, WateringModule};
-use once_cell::sync::{Lazy, OnceCell};
-use std::{
-    io,
-    process::{Child, Command, Stdio},
+use clap::{ Parser, Subcommand };
+use devices::DevicesCommand;
+use hub::{
+    config::Config,
+    modules::{ SensorModule, WateringModule },
+    HubBuilder,
 };
+use std::{ path::{ Path, PathBuf }, process };
 use tokio::{
     signal,
-    sync::{broadcast, Mutex},
+    sync::broadcast,
 };
-use traits::ConfigFile;
 
-mod core;
-pub use core::*;
+mod devices;
 
-mod modules;
-mod mqttc;
-mod settings;
-mod state;
+/// TerraTap hub, connects the sensors and valves over MQTT
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Path to the hub configuration file
+    /// Defaults to `HUB_CONFIG` or the first `config.json` found in the working directory
+    /// or the standard configuration directories
+    #[arg(short, long)]
+    config: Option<PathBuf>,
 
-pub use settings::Settings;
-pub use state::State;
-
-// Re-export the client for easy access
-pub use mqttc::CLIENT;
-
-/// Check if the program is running in release mode and dissallow it.
-/// Currently only running from source is intended.
-fn check_release_mode() {
-    if cfg!(debug_assertions) {
-        return;
-    }
-    eprintln!("Error: Running in release mode is not supported.");
-    std::process::exit(1);
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-/// Spawn the MQTT broker as a child process
-fn spawn_broker() -> io::Result<Child> {
-    Command::new("cargo")
-        .arg("run")
-        .arg("--bin")
-        .arg("mqttd")
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the credentials of the devices allowed to connect to the broker
+    Devices {
+        #[command(subcommand)]
+        command: DevicesCommand,
+    },
 }
 
-// Global handle to the MQTT broker
-static BROKER: OnceCell<Mutex<Child>> = OnceCell::new();
-// Global handle to the settings
-static SETTINGS: OnceCell<Mutex<Settings>> = OnceCell::new();
-// Global handle to the state
-static STATE: OnceCell<Mutex<State>> = OnceCell::new();
-// Global handle to the module manager
-static MODULE_MANAGER: Lazy<Mutex<ModuleManager>> = Lazy::new(|| Mutex::new(ModuleManager::new()));
-
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+    if let Some(Command::Devices { command }) = args.command {
+        let config = resolve(args.config.as_deref());
+        if let Err(error) = devices::run(command, config.credentials.as_deref()) {
+            eprintln!("Failed to update the device credentials: {}", error);
+            process::exit(1);
+        }
+        return;
+    }
     std::env::set_var("RUST_LOG", "debug");
-    check_release_mode();
 
     tracing_subscriber::fmt::init();
 
@@ -75,44 +65,32 @@ async fn main() {
     // # Code above should not be modified, as it ensures the proper operation of the program.
     // # It also guarantees that the program will log anything it does
 
-    // create settings and state handles
-    let settings = Settings::load();
-    let state = State::load();
-    let _ = STATE.set(Mutex::new(state)).unwrap();
-    let _ = SETTINGS.set(Mutex::new(settings.clone())).unwrap();
-
-    // Register the modules
-    let mut manager = MODULE_MANAGER.lock().await;
-    manager.register_module(SensorModule::from(&settings));
-    manager.register_module(WateringModule::from(&settings));
-    // ensure the manager is available for the client
-    drop(manager);
-
-    tracing::info!("TerraTap running... Press Ctrl+C to exit.");
-    tracing::info!("Starting MQTT broker... This may take a few seconds.");
-    let broker = spawn_broker().expect("Failed to spawn broker");
-    let _ = BROKER.set(Mutex::new(broker)).unwrap();
-
-    let client_task = mqttc::run(shutdown_rx);
-
-    // Wait for either Ctrl+C or the client task to finish
-    // The client task should not finish
-    // TODO: auto-restart mechanism instead of just exiting
-    loop {
-        tokio::select! {
-            _ = ctrl_c_task => {
-                break;
-            }
-            _ = client_task.await => {
-                break;
-            }
-        }
+    // Register the modules below the topic namespace of the hub
+    let config = resolve(args.config.as_deref());
+    let hub = HubBuilder::new(config)
+        .module_with(SensorModule::new)
+        .module_with(WateringModule::new)
+        .build()
+        .unwrap_or_else(|error| {
+            tracing::error!("Failed to build the hub: {}", error);
+            process::exit(1);
+        });
+
+    // Run until Ctrl+C, the state and settings are saved on the way out
+    let result = hub.run(shutdown_rx).await;
+    ctrl_c_task.abort();
+    if let Err(error) = result {
+        tracing::error!("Failed to start the MQTT broker: {}", error);
+        process::exit(1);
     }
-    // kill the broker to be sure all tasks are cleaned up
-    let _ = BROKER.get().unwrap().lock().await.kill();
-    // Save the state and settings before exiting
-    STATE.get().unwrap().lock().await.save();
-    SETTINGS.get().unwrap().lock().await.save();
 
     tracing::info!("Thank you for using TerraTap! Until next time!");
 }
+
+/// The configuration of the hub, exits if it is invalid
+fn resolve(path: Option<&Path>) -> Config {
+    Config::resolve(path).unwrap_or_else(|error| {
+        eprintln!("Invalid configuration: {}", error);
+        process::exit(1);
+    })
+}
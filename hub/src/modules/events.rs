@@ -0,0 +1,31 @@
+//! Events of the modules on the event bus of the hub
+
+use chrono::NaiveDateTime;
+
+use crate::Event;
+
+/// The sensor reported whether the soil needs water
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorReported {
+    pub watering_needed: bool,
+    pub at: NaiveDateTime,
+}
+
+impl Event for SensorReported {}
+
+/// A valve requested the watering state
+#[derive(Clone, Debug, PartialEq)]
+pub struct WateringRequested {
+    pub at: NaiveDateTime,
+}
+
+impl Event for WateringRequested {}
+
+/// A valve was told to open, it waters for the open duration
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValveOpened {
+    /// Seconds the valve stays open
+    pub open_duration: u64,
+}
+
+impl Event for ValveOpened {}
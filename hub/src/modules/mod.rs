@@ -1,8 +1,10 @@
+pub mod events;
 mod watering;
 mod sensor;
 mod prelude;
 
+/// Default prefix of the module topics, see `TopicsConfig`
 pub const PREFIX: &str = "home";
 
-pub use watering::WateringModule;
-pub use sensor::SensorModule;
+pub use watering::{ WateringModule, WateringSettings };
+pub use sensor::{ SensorModule, SensorSettings };
@@ -1,4 +1,4 @@
-pub use crate::{ ClientModule, Settings };
+pub use crate::{ ClientModule, Handlers, HubContext, ModuleError, ModuleSettings, Settings };
 pub use serde::{ Deserialize, Serialize };
 
 pub use crate::topic;
@@ -1,10 +1,33 @@
-use std::collections::HashMap;
+use chrono::NaiveTime;
 
-use chrono::{ Duration, NaiveTime };
+use super::{ events::SensorReported, prelude::* };
+use crate::serde::LenientBool;
 
-use super::prelude::*;
+/// The section of the sensor in the settings
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SensorSettings {
+    #[serde(
+        serialize_with = "crate::serde::serialize_naive_time",
+        deserialize_with = "crate::serde::deserialize_naive_time"
+    )]
+    pub check_time: NaiveTime,
+    pub check_duration: u64,
+}
+
+impl Default for SensorSettings {
+    fn default() -> Self {
+        Self {
+            // 5 minutes before the watering at 3:00 AM
+            check_time: NaiveTime::from_hms_opt(2, 55, 0).unwrap(),
+            // Default duration is 30 seconds
+            check_duration: 30,
+        }
+    }
+}
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ModuleSettings)]
+#[settings(topic = "sensor")]
 pub struct SensorModule {
     #[serde(
         serialize_with = "crate::serde::serialize_naive_time",
@@ -12,6 +35,9 @@ pub struct SensorModule {
     )]
     pub check_time: NaiveTime,
     pub check_duration: u64,
+    /// Topic prefix of the hub, not a setting
+    #[serde(skip)]
+    prefix: String,
 }
 
 impl Default for SensorModule {
@@ -22,51 +48,95 @@ impl Default for SensorModule {
 
 impl From<&Settings> for SensorModule {
     fn from(settings: &Settings) -> Self {
+        Self::new(super::PREFIX, settings)
+    }
+}
+
+impl SensorModule {
+    /// Create the module below the topic prefix of the hub
+    pub fn new(prefix: &str, settings: &Settings) -> Self {
+        let settings = settings.module::<SensorSettings>(Self::TOPIC);
         Self {
-            // remove 5 minutes from the check time for the sensor
-            check_time: settings.check_time - Duration::minutes(5),
+            check_time: settings.check_time,
             check_duration: settings.check_duration,
+            prefix: prefix.to_string(),
         }
     }
+
+    /// The sensor reports whether the soil needs water
+    /// The payload is read case-insensitively, devices send "TRUE" as well as "true"
+    async fn watering_needed(&self, context: &HubContext, LenientBool(needed): LenientBool) -> Result<(), ModuleError> {
+        let now = context.now();
+        let mut state_mut = context.state().lock().await;
+        if needed {
+            state_mut.watering_needed = true;
+            tracing::info!("Watering needed: {}", state_mut.watering_needed);
+        } else {
+            tracing::trace!("Sensor reported that no watering is needed");
+        }
+        drop(state_mut);
+        context.events().publish(SensorReported { watering_needed: needed, at: now });
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
 impl ClientModule for SensorModule {
     fn topic(&self) -> String {
-        format!("{}/{}", super::PREFIX, "sensor")
+        topic!(self.prefix, Self::TOPIC)
     }
 
-    async fn handle(&self, topic: &str, payload: &str) {
-        match topic {
-            t if t == topic!(self.topic(), "watering_needed") => {
-                if payload.to_ascii_lowercase() == "true" {
-                    let mut state_mut = crate::STATE.get().unwrap().lock().await;
-                    state_mut.watering_needed = true;
-                    tracing::info!("Watering needed: {}", state_mut.watering_needed);
-                } else {
-                    tracing::trace!(
-                        "Received message on topic '{}' with payload '{}'",
-                        topic,
-                        payload
-                    );
-                }
-            }
-            _ => {}
-        }
+    fn handlers() -> Handlers<Self> {
+        Handlers::new().on("watering_needed", Self::watering_needed)
     }
 
-    fn settings(&self) -> HashMap<String, String> {
-        let mut settings = HashMap::new();
+    /// The watering answers the valves with the reported state
+    fn shares_state(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+    use crate::{ outbox::{ Outbox, OutboxConfig }, Clock, ModuleManager, State };
 
-        let ser = serde_json::to_string(&self).unwrap();
-        let deser = serde_json
-            ::to_value(serde_json::from_str::<SensorModule>(&ser).unwrap())
-            .unwrap();
+    struct FixedClock(NaiveDateTime);
 
-        for (key, value) in deser.as_object().unwrap() {
-            settings.insert(key.clone(), value.to_string());
+    impl Clock for FixedClock {
+        fn now(&self) -> NaiveDateTime {
+            self.0
         }
+    }
+
+    #[tokio::test]
+    async fn test_watering_needed_updates_the_state() {
+        let now = NaiveDateTime::parse_from_str("2024-05-01 03:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let outbox = Outbox::open(OutboxConfig { path: None, ..OutboxConfig::default() });
+        let context = HubContext::new(State::default(), Settings::default(), outbox, FixedClock(now));
+        let module = SensorModule::default();
+        let mut reports = context.events().subscribe::<SensorReported>();
+
+        module.watering_needed(&context, LenientBool(false)).await.unwrap();
+        assert!(!context.state().lock().await.watering_needed);
+
+        module.watering_needed(&context, LenientBool(true)).await.unwrap();
+        assert!(context.state().lock().await.watering_needed);
+
+        assert_eq!(reports.try_recv().unwrap(), SensorReported { watering_needed: false, at: now });
+        assert_eq!(reports.try_recv().unwrap(), SensorReported { watering_needed: true, at: now });
+    }
+
+    #[tokio::test]
+    async fn test_watering_needed_ignores_the_case() {
+        let mut manager = ModuleManager::default();
+        manager.register_module(SensorModule::default());
 
-        settings
+        assert!(manager.handle_message("home/sensor/watering_needed", "TRUE").is_empty());
+        manager.drain().await;
+        assert!(manager.context().state().lock().await.watering_needed);
+        assert!(manager.failures().is_empty());
     }
 }
@@ -1,11 +1,34 @@
-use std::collections::HashMap;
-
-use super::prelude::*;
+use super::{ events::{ ValveOpened, WateringRequested }, prelude::* };
 
 use chrono::NaiveTime;
 use rumqttc::QoS;
+use serde::de::IgnoredAny;
+
+/// The section of the watering in the settings
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WateringSettings {
+    #[serde(
+        serialize_with = "crate::serde::serialize_naive_time",
+        deserialize_with = "crate::serde::deserialize_naive_time"
+    )]
+    pub check_time: NaiveTime,
+    pub open_duration: u64,
+}
 
-#[derive(Serialize, Deserialize)]
+impl Default for WateringSettings {
+    fn default() -> Self {
+        Self {
+            // Default time is 3:00 AM
+            check_time: NaiveTime::from_hms_opt(3, 0, 0).unwrap(),
+            // Default duration is 5 minutes
+            open_duration: 5 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ModuleSettings)]
+#[settings(topic = "watering")]
 pub struct WateringModule {
     #[serde(
         serialize_with = "crate::serde::serialize_naive_time",
@@ -13,6 +36,9 @@ pub struct WateringModule {
     )]
     pub check_time: NaiveTime,
     pub open_duration: u64,
+    /// Topic prefix of the hub, not a setting
+    #[serde(skip)]
+    prefix: String,
 }
 
 impl Default for WateringModule {
@@ -23,55 +49,107 @@ impl Default for WateringModule {
 
 impl From<&Settings> for WateringModule {
     fn from(settings: &Settings) -> Self {
+        Self::new(super::PREFIX, settings)
+    }
+}
+
+impl WateringModule {
+    /// Create the module below the topic prefix of the hub
+    pub fn new(prefix: &str, settings: &Settings) -> Self {
+        let settings = settings.module::<WateringSettings>(Self::TOPIC);
         Self {
             check_time: settings.check_time,
             open_duration: settings.open_duration,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// A valve requests the watering state, the payload does not matter
+    async fn watering_needed(&self, context: &HubContext, _request: IgnoredAny) -> Result<(), ModuleError> {
+        context.events().publish(WateringRequested { at: context.now() });
+        let mut state = context.state().lock().await;
+        // Publish the watering needed state to the MQTT broker so the client can read it
+        // it is queued if the broker is unreachable, so the response is not lost
+        // TODO: Tie this to a client to allow for multiple watering modules
+        let response = topic!(self.topic(), "watering_needed/response");
+        let queued = context.publish(
+            response.as_str(),
+            QoS::ExactlyOnce,
+            false,
+            state.watering_needed.to_string()
+        ).await;
+
+        // If the response was queued, reset the watering needed state
+        // otherwise the state is kept for the next request
+        if !queued {
+            return Err(ModuleError::Publish(response));
+        }
+        let opened = std::mem::take(&mut state.watering_needed);
+        tracing::trace!("Watering needed reset");
+        drop(state);
+        if opened {
+            context.events().publish(ValveOpened { open_duration: self.open_duration });
         }
+        Ok(())
     }
 }
 
 #[async_trait::async_trait]
 impl ClientModule for WateringModule {
     fn topic(&self) -> String {
-        format!("{}/{}", super::PREFIX, "watering")
+        topic!(self.prefix, Self::TOPIC)
+    }
+
+    fn handlers() -> Handlers<Self> {
+        // the responses are published by the module itself
+        Handlers::new()
+            .on("watering_needed", Self::watering_needed)
+            .ignore("watering_needed/response")
     }
 
-    fn settings(&self) -> HashMap<String, String> {
-        let mut settings = HashMap::new();
+    /// A request is answered after the sensor reports that arrived before it
+    fn shares_state(&self) -> bool {
+        true
+    }
+}
 
-        let ser = serde_json::to_string(&self).unwrap();
-        let deser = serde_json
-            ::to_value(serde_json::from_str::<WateringModule>(&ser).unwrap())
-            .unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ outbox::{ Outbox, OutboxConfig }, State, SystemClock };
 
-        for (key, value) in deser.as_object().unwrap() {
-            settings.insert(key.clone(), value.to_string());
-        }
+    fn context(capacity: usize) -> HubContext {
+        let outbox = Outbox::open(OutboxConfig { path: None, capacity, ..OutboxConfig::default() });
+        let state = State { watering_needed: true };
+        HubContext::new(state, Settings::default(), outbox, SystemClock)
+    }
 
-        settings
+    #[tokio::test]
+    async fn test_response_resets_the_state() {
+        let context = context(10);
+        let module = WateringModule::default();
+        let mut requests = context.events().subscribe::<WateringRequested>();
+        let mut opened = context.events().subscribe::<ValveOpened>();
+
+        module.watering_needed(&context, IgnoredAny).await.unwrap();
+        assert!(!context.state().lock().await.watering_needed);
+        assert_eq!(context.outbox().lock().await.len(), 1);
+        assert!(requests.try_recv().is_ok());
+        assert_eq!(opened.try_recv().unwrap(), ValveOpened { open_duration: 5 * 60 });
+
+        // the state was reset, so the next request does not open the valve again
+        module.watering_needed(&context, IgnoredAny).await.unwrap();
+        assert!(requests.try_recv().is_ok());
+        assert!(opened.try_recv().is_err());
     }
 
-    async fn handle(&self, topic: &str, _payload: &str) {
-        match topic {
-            t if t == topic!(self.topic(), "watering_needed") => {
-                let client = crate::CLIENT.get().unwrap().lock().await;
-                let mut state = crate::STATE.get().unwrap().lock().await;
-                // Publish the watering needed state to the MQTT broker so the client can read it
-                // TODO: Tie this to a client to allow for multiple watering modules
-                let res = client.try_publish(
-                    topic!(self.topic(), "watering_needed/response"),
-                    QoS::ExactlyOnce,
-                    false,
-                    state.watering_needed.clone().to_string().as_bytes()
-                );
-
-                // If the publish was successful, reset the watering needed state
-                if res.is_ok() {
-                    state.watering_needed = false;
-                    tracing::trace!("Watering needed reset");
-                }
-            }
-            _ => {}
-        }
+    #[tokio::test]
+    async fn test_dropped_response_keeps_the_state() {
+        let context = context(0);
+        let module = WateringModule::default();
+
+        let error = module.watering_needed(&context, IgnoredAny).await.unwrap_err();
+        assert_eq!(error.to_string(), "failed to publish on 'home/watering/watering_needed/response'");
+        assert!(context.state().lock().await.watering_needed);
     }
 }
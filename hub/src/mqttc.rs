@@ -1,55 +1,134 @@
-use std::time::Duration;
+use std::{ sync::Arc, time::Duration };
 
-use once_cell::sync::OnceCell;
-use rumqttc::{ AsyncClient, MqttOptions };
-use tokio::sync::{ broadcast::Receiver, Mutex };
+use rumqttc::{ AsyncClient, MqttOptions, Publish };
+use tokio::sync::{ broadcast::Receiver, mpsc::{ self, UnboundedReceiver }, Mutex };
 use tracing::span;
 
-pub static CLIENT: OnceCell<Mutex<AsyncClient>> = OnceCell::new();
+use crate::{ config::MqttConfig, HubContext, ModuleManager, Publisher };
 
-pub async fn run(mut shutdown: Receiver<()>) -> tokio::task::JoinHandle<()> {
+/// The state of the connection between the hub and the MQTT broker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+}
+
+/// Exponential backoff for reconnection attempts
+/// The delay doubles with every attempt until it reaches the maximum
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    /// Create a new backoff starting at `initial` and capped at `max`
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// The delay to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    /// Start over with the initial delay, once a connection was established
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// Connect the modules of the manager to the broker
+/// The client is handed to the context of the modules, so their publications reach the broker
+pub async fn run(
+    config: &MqttConfig,
+    manager: Arc<Mutex<ModuleManager>>,
+    mut shutdown: Receiver<()>
+) -> tokio::task::JoinHandle<()> {
     let broker_span = span!(tracing::Level::INFO, "mqtt-client");
     let _ = broker_span.enter();
 
-    let mut mqtt_options = MqttOptions::new("hub", "127.0.0.1", 1883);
-    mqtt_options.set_keep_alive(Duration::from_secs(5));
+    let mqtt_options = MqttOptions::try_from(config).expect("Failed to read the TLS certificates");
+    let scheme = if config.tls.is_some() { "mqtts" } else { "mqtt" };
+    tracing::info!("Connecting to MQTT broker at {}://{}:{}", scheme, config.host, config.port);
+
+    let (client, mut eventloop) = AsyncClient::new(mqtt_options, config.capacity);
+    let context = manager.lock().await.context().clone();
+    context.set_client(client.clone());
+    let deliveries = context.outbox().lock().await.deliveries();
+    tracing::info!("Client created and handed to the modules");
 
-    let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
-    CLIENT.set(Mutex::new(client.clone())).unwrap();
-    tracing::info!("Client created and stored in global static variable");
+    let mut backoff = Backoff::new(
+        Duration::from_millis(config.reconnect_delay),
+        Duration::from_millis(config.max_reconnect_delay)
+    );
+
+    // the modules run on their own task, so the event loop keeps polling while they wait for room in the client
+    let (events, dispatched) = mpsc::unbounded_channel();
+    tokio::spawn(dispatch(Publisher::new(client.clone(), deliveries.clone()), manager, dispatched));
 
     tokio::spawn(async move {
+        let mut state = ConnectionState::Disconnected;
         loop {
             tokio::select! {
                 result = eventloop.poll() => {
-                    if let Ok(notification) = result {
-                        //dbg!("Received = {:#?}", notification.clone());
-                        match notification {
-                            rumqttc::Event::Incoming(incoming) => {
-                                match incoming {
-                                    rumqttc::Incoming::Publish(publish) => {
-                                        let topic = publish.topic;
-                                        let payload = publish.payload;
-
-                                        // aquire the module manager and handle the message
-                                        let manager = crate::MODULE_MANAGER.lock().await;
-                                        manager.handle_message(&topic, std::str::from_utf8(&payload).unwrap()).await;
-                                        // free the manager
-                                        drop(manager);
-                                    }
-                                    rumqttc::Incoming::ConnAck(_) => {
-                                        // Initialize the modules once the connection is acknowledged
-                                        let manager = crate::MODULE_MANAGER.lock().await;
-                                        manager.initialize(&client);
-                                    }
-                                    // for now any other messages are just irgnored
-                                    _ => {
-                                        //tracing::info!("Received = {:#?}", incoming);
-                                    }
+                    match result {
+                        Ok(rumqttc::Event::Incoming(incoming)) => {
+                            match incoming {
+                                rumqttc::Incoming::Publish(publish) => {
+                                    let _ = events.send(Dispatch::Message(publish));
+                                    flush(&context, &client).await;
+                                }
+                                rumqttc::Incoming::ConnAck(_) => {
+                                    tracing::info!("Connected to MQTT broker");
+                                    backoff.reset();
+                                    state = ConnectionState::Connected;
+
+                                    // Publish what was queued while disconnected first, the current settings win over queued ones
+                                    let mut outbox = context.outbox().lock().await;
+                                    outbox.set_connected(true);
+                                    outbox.flush(&client);
+                                    drop(outbox);
+
+                                    let _ = events.send(Dispatch::Connected);
+                                }
+                                // queued publications are only removed once the broker got them
+                                rumqttc::Incoming::PubAck(rumqttc::PubAck { pkid, .. }) | rumqttc::Incoming::PubComp(rumqttc::PubComp { pkid, .. }) => {
+                                    delivered(&context, deliveries.acknowledged(pkid)).await;
+                                    flush(&context, &client).await;
                                 }
+                                // the acknowledgements free the request channel for queued publications
+                                _ => flush(&context, &client).await,
                             }
-                            rumqttc::Event::Outgoing(_outgoing) => {
-                                //tracing::info!("Sent = {:#?}", outgoing);
+                        }
+                        Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Publish(pkid))) => {
+                            delivered(&context, deliveries.written(pkid)).await;
+                        }
+                        Ok(rumqttc::Event::Outgoing(_outgoing)) => {}
+                        Err(error) => {
+                            if state == ConnectionState::Connected {
+                                tracing::warn!("Lost connection to MQTT broker");
+                                state = ConnectionState::Disconnected;
+                                context.outbox().lock().await.set_connected(false);
+                                let _ = events.send(Dispatch::Disconnected);
+                            }
+
+                            // the next poll will reconnect, wait before to not hammer the broker
+                            let delay = backoff.next_delay();
+                            tracing::error!("MQTT connection error: {}, retrying in {:?}", error, delay);
+                            tokio::select! {
+                                _ = tokio::time::sleep(delay) => {}
+                                _ = shutdown.recv() => {
+                                    tracing::info!("Shutting down...");
+                                    break;
+                                }
                             }
                         }
                     }
@@ -62,3 +141,182 @@ pub async fn run(mut shutdown: Receiver<()>) -> tokio::task::JoinHandle<()> {
         }
     })
 }
+
+/// Work for the modules, handed from the event loop to the dispatcher
+#[derive(Debug)]
+enum Dispatch {
+    Connected,
+    Disconnected,
+    Message(Publish),
+}
+
+/// Run the modules in the order the events arrived, until the event loop is gone
+async fn dispatch(publisher: Publisher, manager: Arc<Mutex<ModuleManager>>, mut events: UnboundedReceiver<Dispatch>) {
+    while let Some(event) = events.recv().await {
+        // aquire the module manager, it is freed at the end of every event
+        let manager = manager.lock().await;
+        match event {
+            Dispatch::Message(publish) => {
+                match std::str::from_utf8(&publish.payload) {
+                    // only queued, the modules handle it on their own tasks
+                    Ok(payload) => {
+                        manager.handle_message(&publish.topic, payload);
+                    }
+                    Err(error) => tracing::warn!("Failed to handle a message: payload on '{}' is no UTF-8: {}", publish.topic, error),
+                }
+            }
+            Dispatch::Connected => {
+                // (Re-)Initialize the modules every time the connection is acknowledged
+                // the broker may have lost the subscriptions and retained settings
+                let outcomes = manager.initialize(&publisher).await;
+                let failed = outcomes.into_iter().filter(|outcome| !outcome.is_ok()).collect::<Vec<_>>();
+                if !failed.is_empty() {
+                    let mut outbox = manager.context().outbox().lock().await;
+                    for outcome in failed {
+                        outbox.push(outcome.message);
+                    }
+                    tracing::warn!("Settings were queued, {} publications are waiting for the broker", outbox.len());
+                }
+                manager.connection_changed(ConnectionState::Connected).await;
+            }
+            Dispatch::Disconnected => manager.connection_changed(ConnectionState::Disconnected).await,
+        }
+    }
+}
+
+/// Remove a publication of the outbox the broker got
+async fn delivered(context: &HubContext, id: Option<u64>) {
+    if let Some(id) = id {
+        context.outbox().lock().await.delivered(id);
+    }
+}
+
+/// Hand queued publications to the client, if there are any
+async fn flush(context: &HubContext, client: &AsyncClient) {
+    let mut outbox = context.outbox().lock().await;
+    if !outbox.is_empty() {
+        outbox.flush(client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ net::TcpListener, path::Path };
+
+    use mqttd::certs::{ CertificateAuthority, CertificateFiles, CA_NAME };
+    use rumqttc::{ Event, Incoming };
+
+    use super::*;
+    use crate::config::TlsConfig;
+
+    /// Find a port that is currently not in use
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    /// Start a broker with only a TLS listener, using the certificates in the directory
+    async fn start_tls_broker(dir: &Path) -> u16 {
+        let authority = CertificateAuthority::load_or_generate(dir).unwrap();
+        let server = authority.issue_server(&["localhost".to_string()], dir).unwrap();
+
+        let (plain_port, tls_port) = (free_port(), free_port());
+        let mut config = mqttd::default_config();
+        config.v5 = None;
+        mqttd::Overrides {
+            v4_listen: Some(format!("127.0.0.1:{}", plain_port).parse().unwrap()),
+            tls_listen: Some(format!("127.0.0.1:{}", tls_port).parse().unwrap()),
+            tls_cert: Some(server.cert),
+            tls_key: Some(server.key),
+            ..Default::default()
+        }
+            .apply(&mut config)
+            .unwrap();
+        mqttd::validate(&config).unwrap();
+
+        let broker = mqttd::start(config, mqttd::default_extensions(), None).unwrap();
+        broker.ready(Duration::from_secs(5)).await.unwrap();
+        tls_port
+    }
+
+    /// Poll the event loop until the broker acknowledges the connection or an error occurs
+    async fn connect(config: &MqttConfig) -> Result<(), rumqttc::ConnectionError> {
+        let options = MqttOptions::try_from(config).unwrap();
+        let (_client, mut eventloop) = AsyncClient::new(options, config.capacity);
+        loop {
+            if let Event::Incoming(Incoming::ConnAck(_)) = eventloop.poll().await? {
+                return Ok(());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_connection_with_pinned_ca() {
+        let dir = std::env::temp_dir().join("hub-test-tls-pinned");
+        let _ = std::fs::remove_dir_all(&dir);
+        let port = start_tls_broker(&dir).await;
+
+        let config = MqttConfig {
+            host: "localhost".to_string(),
+            port,
+            client_id: "hub-tls-test".to_string(),
+            tls: Some(TlsConfig {
+                ca: CertificateFiles::new(&dir, CA_NAME).cert,
+                client_cert: None,
+                client_key: None,
+            }),
+            ..Default::default()
+        };
+        let result = tokio::time::timeout(Duration::from_secs(5), connect(&config)).await;
+        assert!(matches!(result, Ok(Ok(()))));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_connection_rejects_other_ca() {
+        let dir = std::env::temp_dir().join("hub-test-tls-other-ca");
+        let other_dir = std::env::temp_dir().join("hub-test-tls-other-ca-client");
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&other_dir);
+        let port = start_tls_broker(&dir).await;
+        CertificateAuthority::load_or_generate(&other_dir).unwrap();
+
+        let config = MqttConfig {
+            host: "localhost".to_string(),
+            port,
+            client_id: "hub-tls-test-other".to_string(),
+            tls: Some(TlsConfig {
+                ca: CertificateFiles::new(&other_dir, CA_NAME).cert,
+                client_cert: None,
+                client_key: None,
+            }),
+            ..Default::default()
+        };
+        let result = tokio::time::timeout(Duration::from_secs(5), connect(&config)).await;
+        assert!(matches!(result, Ok(Err(_))));
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(other_dir).unwrap();
+    }
+
+    #[test]
+    fn test_backoff_doubles_until_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(3));
+
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(3));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_backoff_reset() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(3));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,446 @@
+use std::{ collections::{ HashMap, VecDeque }, io, path::{ Path, PathBuf }, sync::Arc };
+
+use rumqttc::{ AsyncClient, QoS };
+use serde::{ Deserialize, Serialize };
+
+/// Queue of publications that must not get lost while the broker is unreachable
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    /// File the queued publications are kept in across restarts, only kept in memory if not set
+    pub path: Option<PathBuf>,
+    /// Maximum number of publications waiting for the broker, queued or in flight
+    pub capacity: usize,
+    /// Maximum size of the topics and payloads waiting for the broker in bytes
+    pub max_bytes: usize,
+    /// Which publication gives way once a limit is reached
+    pub policy: DropPolicy,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            path: Some(PathBuf::from("outbox.json")),
+            capacity: 1000,
+            max_bytes: 1024 * 1024,
+            policy: DropPolicy::DropOldest,
+        }
+    }
+}
+
+/// What to do with a publication that does not fit into the outbox anymore
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    /// Drop the oldest queued publications to make room for the new one
+    #[default]
+    DropOldest,
+    /// Keep the queued publications and drop the new one
+    DropNewest,
+}
+
+/// A queued publication
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// QoS as its number, rumqttc's `QoS` can not be serialized
+    pub qos: u8,
+    pub retain: bool,
+}
+
+impl Message {
+    pub fn new(topic: impl Into<String>, qos: QoS, retain: bool, payload: impl Into<Vec<u8>>) -> Self {
+        Self { topic: topic.into(), payload: payload.into(), qos: qos as u8, retain }
+    }
+
+    /// Bytes counted against `max_bytes`
+    fn size(&self) -> usize {
+        self.topic.len() + self.payload.len()
+    }
+}
+
+/// Tells which acknowledgements of the client belong to publications of the outbox
+/// rumqttc only reports packet ids, in the order the publications were handed to the client,
+/// so every publication of the client goes through the outbox or a `Publisher`, which note it here
+#[derive(Debug, Default)]
+pub struct Deliveries {
+    /// Held while a publication is handed to the client, so the notes keep the order of the client
+    sending: tokio::sync::Mutex<()>,
+    state: std::sync::Mutex<DeliveryState>,
+}
+
+#[derive(Debug, Default)]
+struct DeliveryState {
+    /// Publications handed to the client but not written yet, by their id in the outbox
+    /// `None` for publications that are not in the outbox
+    handed_over: VecDeque<Option<u64>>,
+    /// Written publications waiting for their acknowledgement, by packet id
+    unacknowledged: HashMap<u16, Option<u64>>,
+}
+
+impl Deliveries {
+    /// Wait for the turn to hand a publication to the client
+    pub async fn sending(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.sending.lock().await
+    }
+
+    /// Note a publication that was handed to the client, while holding the turn
+    pub fn handed_over(&self, id: Option<u64>) {
+        self.state.lock().unwrap().handed_over.push_back(id);
+    }
+
+    /// Forget the last publication noted, the client did not take it
+    pub fn withdrawn(&self) {
+        self.state.lock().unwrap().handed_over.pop_back();
+    }
+
+    /// The event loop wrote a publication to the broker
+    /// Returns the id of an outbox publication without acknowledgement (QoS 0), it is delivered already
+    pub fn written(&self, pkid: u16) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        // the publications of a lost connection are written again with their packet id
+        if pkid != 0 && state.unacknowledged.contains_key(&pkid) {
+            return None;
+        }
+        let id = state.handed_over.pop_front().flatten();
+        if pkid == 0 {
+            return id;
+        }
+        state.unacknowledged.insert(pkid, id);
+        None
+    }
+
+    /// The broker acknowledged a publication (PubAck for QoS 1, PubComp for QoS 2)
+    /// Returns its id if it is a publication of the outbox
+    pub fn acknowledged(&self, pkid: u16) -> Option<u64> {
+        self.state.lock().unwrap().unacknowledged.remove(&pkid).flatten()
+    }
+}
+
+/// Publications waiting for the broker, handed to the client in the order they were queued
+/// Handed over publications stay in the outbox until the broker acknowledged them
+#[derive(Debug)]
+pub struct Outbox {
+    config: OutboxConfig,
+    messages: VecDeque<Message>,
+    /// Publications handed to the client that were not acknowledged yet, by id
+    in_flight: VecDeque<(u64, Message)>,
+    next_id: u64,
+    /// Size of the queued publications and the ones in flight
+    bytes: usize,
+    connected: bool,
+    deliveries: Arc<Deliveries>,
+}
+
+impl Outbox {
+    /// Create the outbox with the publications left from the last run
+    pub fn open(config: OutboxConfig) -> Self {
+        let messages = match config.path.as_deref().map(load) {
+            Some(Ok(messages)) => messages,
+            Some(Err(error)) if error.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Some(Err(error)) => {
+                tracing::error!("Failed to load the queued publications, starting empty: {}", error);
+                VecDeque::new()
+            }
+            None => VecDeque::new(),
+        };
+        if !messages.is_empty() {
+            tracing::info!("{} publications are waiting for the broker", messages.len());
+        }
+
+        let bytes = messages.iter().map(Message::size).sum();
+        Self {
+            config,
+            messages,
+            in_flight: VecDeque::new(),
+            next_id: 0,
+            bytes,
+            connected: false,
+            deliveries: Arc::default(),
+        }
+    }
+
+    /// Number of publications that were not acknowledged yet, handed over or not
+    pub fn len(&self) -> usize {
+        self.in_flight.len() + self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The publications that were not acknowledged yet, the oldest first
+    pub fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.in_flight.iter().map(|(_, message)| message).chain(&self.messages)
+    }
+
+    /// The notes of the handed over publications, shared with the event loop and the `Publisher`
+    pub fn deliveries(&self) -> Arc<Deliveries> {
+        self.deliveries.clone()
+    }
+
+    /// Whether the client is connected, publications are only handed over while it is
+    pub fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+    }
+
+    /// Queue a publication according to the limits and the drop policy
+    /// Returns false if it was dropped
+    pub fn push(&mut self, message: Message) -> bool {
+        if message.size() > self.config.max_bytes || self.config.capacity == 0 {
+            tracing::warn!("Dropping the publication on '{}', it exceeds the outbox limits", message.topic);
+            return false;
+        }
+
+        while self.is_full(&message) {
+            match self.config.policy {
+                DropPolicy::DropOldest => {
+                    // the publications in flight are with the client already, only queued ones can make room
+                    let Some(dropped) = self.messages.pop_front() else {
+                        tracing::warn!("Outbox is full of publications in flight, dropping the publication on '{}'", message.topic);
+                        return false;
+                    };
+                    self.bytes -= dropped.size();
+                    tracing::warn!("Outbox is full, dropping the oldest publication on '{}'", dropped.topic);
+                }
+                DropPolicy::DropNewest => {
+                    tracing::warn!("Outbox is full, dropping the publication on '{}'", message.topic);
+                    return false;
+                }
+            }
+        }
+
+        self.bytes += message.size();
+        self.messages.push_back(message);
+        self.save();
+        true
+    }
+
+    /// Whether the publication does not fit, the publications in flight count toward the limits
+    fn is_full(&self, message: &Message) -> bool {
+        self.len() >= self.config.capacity || self.bytes + message.size() > self.config.max_bytes
+    }
+
+    /// Hand the queued publications to the client in order while connected
+    /// Stops at the first one the client can not take, so the order is kept
+    /// Nothing is handed over while a `Publisher` waits for room in the client, the next flush catches up
+    /// Returns the number of publications handed over
+    pub fn flush(&mut self, client: &AsyncClient) -> usize {
+        if !self.connected {
+            return 0;
+        }
+        let deliveries = self.deliveries.clone();
+        let Ok(_sending) = deliveries.sending.try_lock() else {
+            return 0;
+        };
+
+        let mut flushed = 0;
+        while let Some(message) = self.messages.front() {
+            let qos = rumqttc::qos(message.qos).unwrap_or(QoS::AtLeastOnce);
+            deliveries.handed_over(Some(self.next_id));
+            if client.try_publish(&message.topic, qos, message.retain, message.payload.clone()).is_err() {
+                deliveries.withdrawn();
+                break;
+            }
+            let message = self.messages.pop_front().unwrap();
+            self.in_flight.push_back((self.next_id, message));
+            self.next_id += 1;
+            flushed += 1;
+        }
+
+        if flushed > 0 {
+            tracing::debug!("Published {} queued messages, {} left", flushed, self.messages.len());
+        }
+        flushed
+    }
+
+    /// The broker got a handed over publication, it is removed for good
+    pub fn delivered(&mut self, id: u64) {
+        if let Some(position) = self.in_flight.iter().position(|(in_flight, _)| *in_flight == id) {
+            let (_, message) = self.in_flight.remove(position).unwrap();
+            self.bytes -= message.size();
+            self.save();
+        }
+    }
+
+    /// Keep the queue on disk, so it survives a restart
+    /// The publications in flight are kept as well, they are published again after a restart
+    fn save(&self) {
+        if let Some(path) = &self.config.path {
+            if let Err(error) = save(path, self.messages()) {
+                tracing::error!("Failed to save the queued publications to '{}': {}", path.display(), error);
+            }
+        }
+    }
+}
+
+fn load(path: &Path) -> io::Result<VecDeque<Message>> {
+    let content = std::fs::read_to_string(path)?;
+    serde_json::from_str(&content).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+/// Replace the file at once, so a crash does not leave half a queue behind
+/// The file is removed once nothing is waiting anymore
+fn save<'a>(path: &Path, messages: impl Iterator<Item = &'a Message>) -> io::Result<()> {
+    let messages = messages.collect::<Vec<_>>();
+    if messages.is_empty() {
+        return match std::fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        };
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_string(&messages)?)?;
+    std::fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::MqttOptions;
+
+    use super::*;
+
+    fn config(policy: DropPolicy) -> OutboxConfig {
+        OutboxConfig { path: None, capacity: 2, max_bytes: 64, policy }
+    }
+
+    fn message(topic: &str) -> Message {
+        Message::new(topic, QoS::AtLeastOnce, false, "true")
+    }
+
+    fn topics(outbox: &Outbox) -> Vec<&str> {
+        outbox.messages.iter().map(|message| message.topic.as_str()).collect()
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let mut outbox = Outbox::open(config(DropPolicy::DropOldest));
+        assert!(outbox.push(message("home/1")));
+        assert!(outbox.push(message("home/2")));
+        assert!(outbox.push(message("home/3")));
+        assert_eq!(topics(&outbox), vec!["home/2", "home/3"]);
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let mut outbox = Outbox::open(config(DropPolicy::DropNewest));
+        assert!(outbox.push(message("home/1")));
+        assert!(outbox.push(message("home/2")));
+        assert!(!outbox.push(message("home/3")));
+        assert_eq!(topics(&outbox), vec!["home/1", "home/2"]);
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        let mut outbox = Outbox::open(OutboxConfig { capacity: 10, ..config(DropPolicy::DropOldest) });
+        assert!(!outbox.push(Message::new("home/large", QoS::AtLeastOnce, false, vec![0; 64])));
+        assert!(outbox.push(Message::new("home/1", QoS::AtLeastOnce, false, vec![0; 30])));
+        assert!(outbox.push(Message::new("home/2", QoS::AtLeastOnce, false, vec![0; 30])));
+        assert_eq!(topics(&outbox), vec!["home/2"]);
+        assert_eq!(outbox.bytes, 36);
+    }
+
+    #[tokio::test]
+    async fn test_survives_restart() {
+        let path = std::env::temp_dir().join("hub-test-outbox.json");
+        let _ = std::fs::remove_file(&path);
+        let config = OutboxConfig { path: Some(path.clone()), ..config(DropPolicy::DropOldest) };
+
+        let mut outbox = Outbox::open(config.clone());
+        outbox.push(Message::new("home/watering/watering_needed/response", QoS::ExactlyOnce, false, "true"));
+        outbox.push(message("home/2"));
+        drop(outbox);
+
+        let mut outbox = Outbox::open(config);
+        assert_eq!(topics(&outbox), vec!["home/watering/watering_needed/response", "home/2"]);
+        assert_eq!(outbox.messages[0].qos, QoS::ExactlyOnce as u8);
+
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("outbox-test", "127.0.0.1", 1883), 10);
+        outbox.set_connected(true);
+        outbox.flush(&client);
+        // kept until the broker acknowledged them
+        assert!(path.exists());
+        let deliveries = outbox.deliveries();
+        for pkid in [1, 2] {
+            assert_eq!(deliveries.written(pkid), None);
+            outbox.delivered(deliveries.acknowledged(pkid).unwrap());
+        }
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_publications_stay_until_acknowledged() {
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("outbox-test", "127.0.0.1", 1883), 10);
+        let mut outbox = Outbox::open(OutboxConfig { capacity: 10, max_bytes: 1024, ..config(DropPolicy::DropOldest) });
+        outbox.push(message("home/1"));
+        outbox.push(message("home/2"));
+        let deliveries = outbox.deliveries();
+        // a publication of a `Publisher` was handed to the client first
+        deliveries.handed_over(None);
+        outbox.set_connected(true);
+        assert_eq!(outbox.flush(&client), 2);
+        assert_eq!(outbox.len(), 2);
+
+        for pkid in [1, 2, 3] {
+            assert_eq!(deliveries.written(pkid), None);
+        }
+        // written again after a reconnect, nothing new was handed over
+        assert_eq!(deliveries.written(2), None);
+        assert_eq!(deliveries.acknowledged(1), None);
+        outbox.delivered(deliveries.acknowledged(3).unwrap());
+        let topics = outbox.messages().map(|message| message.topic.as_str()).collect::<Vec<_>>();
+        assert_eq!(topics, vec!["home/1"]);
+    }
+
+    #[tokio::test]
+    async fn test_publications_in_flight_count_toward_the_capacity() {
+        // the client takes a single publication, as its event loop is never polled
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("outbox-test", "127.0.0.1", 1883), 1);
+        let mut outbox = Outbox::open(OutboxConfig { capacity: 3, max_bytes: 1024, ..config(DropPolicy::DropOldest) });
+        outbox.push(message("home/1"));
+        outbox.push(message("home/2"));
+        outbox.set_connected(true);
+        assert_eq!(outbox.flush(&client), 1);
+
+        // the oldest queued publication makes room, the one in flight stays
+        assert!(outbox.push(message("home/3")));
+        assert!(outbox.push(message("home/4")));
+        assert_eq!(outbox.len(), 3);
+        let topics = outbox.messages().map(|message| message.topic.as_str()).collect::<Vec<_>>();
+        assert_eq!(topics, vec!["home/1", "home/3", "home/4"]);
+    }
+
+    #[tokio::test]
+    async fn test_full_of_publications_in_flight() {
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("outbox-test", "127.0.0.1", 1883), 1);
+        let mut outbox = Outbox::open(OutboxConfig { capacity: 1, max_bytes: 1024, ..config(DropPolicy::DropOldest) });
+        let deliveries = outbox.deliveries();
+        outbox.push(message("home/1"));
+        outbox.set_connected(true);
+        assert_eq!(outbox.flush(&client), 1);
+
+        // there is no room until the broker acknowledged the publication in flight
+        assert!(!outbox.push(message("home/2")));
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(deliveries.written(1), None);
+        outbox.delivered(deliveries.acknowledged(1).unwrap());
+        assert!(outbox.push(message("home/2")));
+        assert_eq!(topics(&outbox), vec!["home/2"]);
+    }
+
+    #[tokio::test]
+    async fn test_flush_keeps_order() {
+        // the event loop is never polled, so the client takes as many publications as its channel holds
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("outbox-test", "127.0.0.1", 1883), 2);
+        let mut outbox = Outbox::open(OutboxConfig { capacity: 10, max_bytes: 1024, ..config(DropPolicy::DropOldest) });
+        for topic in ["home/1", "home/2", "home/3"] {
+            outbox.push(message(topic));
+        }
+
+        assert_eq!(outbox.flush(&client), 0);
+        outbox.set_connected(true);
+        assert_eq!(outbox.flush(&client), 2);
+        assert_eq!(topics(&outbox), vec!["home/3"]);
+    }
+}
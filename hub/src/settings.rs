@@ -1,33 +1,169 @@
-use chrono::NaiveTime;
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-use crate::traits::ConfigFile;
+use chrono::{ Duration, NaiveTime };
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+use serde_json::{ Map, Value };
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+use crate::{ modules::{ SensorModule, WateringModule }, traits::ConfigFile };
+
+/// The settings of the modules, a section per module keyed by its name
+/// A module reads its section on top of the shared defaults into its own settings type,
+/// the settings of the configuration win over both, see `HubBuilder`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Layout")]
 pub struct Settings {
-    #[serde(
-        serialize_with = "crate::serde::serialize_naive_time",
-        deserialize_with = "crate::serde::deserialize_naive_time"
-    )]
-    pub check_time: NaiveTime,
-    pub check_duration: u64,
-    pub open_duration: u64,
+    /// Inherited by every module, unless its section sets them
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub defaults: Map<String, Value>,
+    #[serde(flatten)]
+    pub modules: BTreeMap<String, Map<String, Value>>,
+}
+
+/// The layouts of the settings file, the legacy one is migrated on load
+/// A file matching neither is invalid, the hub falls back to the default settings and logs it
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Layout {
+    Sections {
+        #[serde(default)]
+        defaults: Map<String, Value>,
+        #[serde(flatten)]
+        modules: BTreeMap<String, Map<String, Value>>,
+    },
+    Legacy(LegacySettings),
+}
+
+/// The flat settings of the versions before the sections, every module read them all
+/// Only read to migrate the files of those versions, back then the sensor and the watering were the only modules,
+/// so new modules start with their own section instead
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LegacySettings {
+    #[serde(deserialize_with = "crate::serde::deserialize_naive_time")]
+    check_time: NaiveTime,
+    check_duration: u64,
+    open_duration: u64,
 }
 
-impl Default for Settings {
+/// The defaults of those versions
+impl Default for LegacySettings {
     fn default() -> Self {
         Self {
-            // Default time is 3:00 AM
             check_time: NaiveTime::from_hms_opt(3, 0, 0).unwrap(),
-            // Default duration is 30 seconds
             check_duration: 30,
-            // Default duration is 5 minutes
             open_duration: 5 * 60,
         }
     }
 }
 
+impl From<Layout> for Settings {
+    fn from(layout: Layout) -> Self {
+        match layout {
+            Layout::Sections { defaults, modules } => Self { defaults, modules },
+            Layout::Legacy(flat) => {
+                tracing::info!("Migrating the flat settings to a section per module");
+                let time = |time: NaiveTime| Value::String(time.format("%H:%M").to_string());
+                let mut settings = Self::default();
+                settings.defaults.insert("check_time".to_string(), time(flat.check_time));
+                // the sensor checked 5 minutes before the shared check time
+                let sensor = settings.modules.entry(SensorModule::TOPIC.to_string()).or_default();
+                sensor.insert("check_time".to_string(), time(flat.check_time - Duration::minutes(5)));
+                sensor.insert("check_duration".to_string(), flat.check_duration.into());
+                let watering = settings.modules.entry(WateringModule::TOPIC.to_string()).or_default();
+                watering.insert("open_duration".to_string(), flat.open_duration.into());
+                settings
+            }
+        }
+    }
+}
+
+impl Settings {
+    /// The settings of a module, its section on top of the shared defaults
+    /// Settings missing in both come from the default of the settings type
+    pub fn module<T: DeserializeOwned + Default>(&self, name: &str) -> T {
+        let mut merged = self.defaults.clone();
+        if let Some(section) = self.modules.get(name) {
+            merged.extend(section.clone());
+        }
+        serde_json::from_value(Value::Object(merged)).unwrap_or_else(|error| {
+            tracing::error!("Invalid settings of module '{}', using its defaults: {}", name, error);
+            T::default()
+        })
+    }
+
+    /// Replace the section of a module
+    pub fn set_module<T: Serialize>(&mut self, name: &str, settings: &T) -> serde_json::Result<()> {
+        match serde_json::to_value(settings)? {
+            Value::Object(section) => {
+                self.modules.insert(name.to_string(), section);
+                Ok(())
+            }
+            _ => Err(serde::ser::Error::custom("the settings of a module have to be an object")),
+        }
+    }
+}
+
 impl ConfigFile<&'static str> for Settings {
     const PATH: &'static str = "settings.json";
     type Config = Self;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(default)]
+    struct PumpSettings {
+        check_time: String,
+        speed: u8,
+    }
+
+    impl Default for PumpSettings {
+        fn default() -> Self {
+            Self { check_time: "00:00".to_string(), speed: 1 }
+        }
+    }
+
+    #[test]
+    fn test_section_inherits_the_defaults() {
+        let settings = serde_json::from_str::<Settings>(
+            r#"{"defaults":{"check_time":"03:00","speed":2},"pump":{"speed":3},"valve":{}}"#
+        ).unwrap();
+        assert_eq!(settings.module::<PumpSettings>("pump"), PumpSettings { check_time: "03:00".to_string(), speed: 3 });
+        assert_eq!(settings.module::<PumpSettings>("missing").speed, 2);
+        assert_eq!(Settings::default().module::<PumpSettings>("pump"), PumpSettings::default());
+    }
+
+    #[test]
+    fn test_flat_settings_are_migrated() {
+        let settings = serde_json::from_str::<Settings>(
+            r#"{"check_time":"03:00","check_duration":30,"open_duration":300}"#
+        ).unwrap();
+        assert_eq!(settings.defaults["check_time"], "03:00");
+        assert_eq!(settings.modules["sensor"]["check_time"], "02:55");
+        assert_eq!(settings.modules["sensor"]["check_duration"], 30);
+        assert_eq!(settings.modules["watering"]["open_duration"], 300);
+
+        // saved with the sections, which load again as they are
+        let saved = serde_json::to_string(&settings).unwrap();
+        assert_eq!(serde_json::from_str::<Settings>(&saved).unwrap(), settings);
+
+        // settings missing in the flat file had defaults back then
+        let settings = serde_json::from_str::<Settings>(r#"{"check_time":"04:00"}"#).unwrap();
+        assert_eq!(settings.modules["sensor"]["check_time"], "03:55");
+        assert_eq!(settings.modules["sensor"]["check_duration"], 30);
+        assert_eq!(settings.modules["watering"]["open_duration"], 300);
+    }
+
+    #[test]
+    fn test_invalid_settings_are_an_error() {
+        assert!(serde_json::from_str::<Settings>(r#"{"check_time":"soon"}"#).is_err());
+        assert!(serde_json::from_str::<Settings>(r#"{"pump":3}"#).is_err());
+
+        let path = std::env::temp_dir().join("hub-test-invalid-settings.json");
+        std::fs::write(&path, r#"{"pump":3}"#).unwrap();
+        assert_eq!(Settings::load_from(&path).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        std::fs::remove_file(path).unwrap();
+    }
+}
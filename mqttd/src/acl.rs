@@ -0,0 +1,291 @@
+use std::{ borrow::Cow, collections::BTreeMap, path::PathBuf, sync::atomic::{ AtomicU64, Ordering } };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::auth::Credentials;
+
+/// Role of a device, decides which topics it may publish and subscribe to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// The hub itself, usually allowed everything
+    Hub,
+    /// Reports measurements
+    Sensor,
+    /// Requests watering and receives the responses
+    Valve,
+    /// Observes the system without controlling it
+    Dashboard,
+}
+
+/// Topic filters a role is allowed to use
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rules {
+    /// Filters the topics of published messages (and last wills) have to match
+    #[serde(default)]
+    pub publish: Vec<String>,
+    /// Filters that have to cover the filters of subscriptions
+    #[serde(default)]
+    pub subscribe: Vec<String>,
+}
+
+/// The `[acl]` section of the broker configuration
+/// The filters of the rules can contain `{prefix}` and `{settings}`, so they follow the topics of the hub
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclConfig {
+    /// Topic prefix of the hub, like `home` for `home/sensor/...`
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Root of the settings topics of the hub, like `settings` for `settings/home/sensor/...`
+    #[serde(default = "default_settings")]
+    pub settings: String,
+    /// Rules per role, roles without rules are not allowed anything
+    #[serde(default)]
+    pub roles: BTreeMap<Role, Rules>,
+}
+
+fn default_prefix() -> String {
+    "home".to_string()
+}
+
+fn default_settings() -> String {
+    "settings".to_string()
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        Self { prefix: default_prefix(), settings: default_settings(), roles: BTreeMap::new() }
+    }
+}
+
+impl AclConfig {
+    /// Whether the role may publish to the topic
+    pub fn allows_publish(&self, role: Role, topic: &str) -> bool {
+        self.roles
+            .get(&role)
+            .is_some_and(|rules| rules.publish.iter().any(|filter| matches(&self.expand(filter), topic)))
+    }
+
+    /// Whether the role may subscribe to the filter
+    /// Shared subscriptions are checked against the filter without the `$share/<group>/` prefix
+    pub fn allows_subscribe(&self, role: Role, filter: &str) -> bool {
+        let filter = strip_share(filter);
+        self.roles
+            .get(&role)
+            .is_some_and(|rules| rules.subscribe.iter().any(|allowed| covers(&self.expand(allowed), filter)))
+    }
+
+    /// Check the prefixes and the placeholders of the filters, before the broker starts
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [("prefix", &self.prefix), ("settings", &self.settings)] {
+            if value.is_empty() || value.contains(['+', '#']) {
+                return Err(format!("the ACL {} '{}' has to be a topic without wildcards", name, value));
+            }
+        }
+        let filters = self.roles.values().flat_map(|rules| rules.publish.iter().chain(&rules.subscribe));
+        for filter in filters {
+            if self.expand(filter).contains(['{', '}']) {
+                return Err(format!("the ACL filter '{}' has an unknown placeholder", filter));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the placeholders of a filter
+    fn expand<'a>(&self, filter: &'a str) -> Cow<'a, str> {
+        if !filter.contains('{') {
+            return Cow::Borrowed(filter);
+        }
+        Cow::Owned(filter.replace("{prefix}", &self.prefix).replace("{settings}", &self.settings))
+    }
+}
+
+/// Number of denied requests since the broker started
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Denials {
+    pub publish: u64,
+    pub subscribe: u64,
+}
+
+/// Access control of a running broker
+/// The roles of the devices are taken from the credential store, which is read on every
+/// connection like for the authentication
+#[derive(Debug)]
+pub struct AccessControl {
+    config: AclConfig,
+    credentials: PathBuf,
+    denied_publish: AtomicU64,
+    denied_subscribe: AtomicU64,
+}
+
+impl AccessControl {
+    pub fn new(config: AclConfig, credentials: PathBuf) -> Self {
+        Self {
+            config,
+            credentials,
+            denied_publish: AtomicU64::new(0),
+            denied_subscribe: AtomicU64::new(0),
+        }
+    }
+
+    /// The role of the device with the username, `None` if it has no role assigned
+    pub fn role(&self, username: &str) -> Option<Role> {
+        match Credentials::load(&self.credentials) {
+            Ok(credentials) => credentials.role(username),
+            Err(error) => {
+                tracing::error!("Failed to read the roles from '{}': {}", self.credentials.display(), error);
+                None
+            }
+        }
+    }
+
+    /// Check a publication, denials are logged and counted
+    pub fn check_publish(&self, client_id: &str, role: Option<Role>, topic: &str) -> bool {
+        if role.is_some_and(|role| self.config.allows_publish(role, topic)) {
+            return true;
+        }
+        self.denied_publish.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!("Denied publish to '{}' for client '{}' with role {:?}", topic, client_id, role);
+        false
+    }
+
+    /// Check a subscription, denials are logged and counted
+    pub fn check_subscribe(&self, client_id: &str, role: Option<Role>, filter: &str) -> bool {
+        if role.is_some_and(|role| self.config.allows_subscribe(role, filter)) {
+            return true;
+        }
+        self.denied_subscribe.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!("Denied subscription to '{}' for client '{}' with role {:?}", filter, client_id, role);
+        false
+    }
+
+    /// Number of denied requests so far
+    pub fn denials(&self) -> Denials {
+        Denials {
+            publish: self.denied_publish.load(Ordering::Relaxed),
+            subscribe: self.denied_subscribe.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Whether the topic matches the filter
+/// Wildcards at the first level do not match topics starting with `$`
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => {
+                return true;
+            }
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => {
+                return false;
+            }
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Whether every topic matched by `filter` is also matched by `allowed`
+pub fn covers(allowed: &str, filter: &str) -> bool {
+    if filter.starts_with('$') && (allowed.starts_with('+') || allowed.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    for level in allowed.split('/') {
+        match (level, filter_levels.next()) {
+            ("#", _) => {
+                return true;
+            }
+            // a single level wildcard covers anything but a multi level one
+            ("+", Some(filter_level)) if filter_level != "#" => {}
+            (level, Some(filter_level)) if level == filter_level => {}
+            _ => {
+                return false;
+            }
+        }
+    }
+    filter_levels.next().is_none()
+}
+
+/// Remove the `$share/<group>/` prefix of shared subscriptions
+fn strip_share(filter: &str) -> &str {
+    filter
+        .strip_prefix("$share/")
+        .and_then(|rest| rest.split_once('/'))
+        .map_or(filter, |(_, filter)| filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AclConfig {
+        let mut roles = BTreeMap::new();
+        roles.insert(Role::Sensor, Rules {
+            publish: vec!["{prefix}/sensor/+".to_string()],
+            subscribe: vec!["{settings}/{prefix}/sensor/#".to_string()],
+        });
+        AclConfig { roles, ..Default::default() }
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("home/sensor/+", "home/sensor/watering_needed"));
+        assert!(!matches("home/sensor/+", "home/sensor/watering_needed/response"));
+        assert!(matches("home/#", "home"));
+        assert!(matches("#", "settings/home/sensor/interval"));
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("home/sensor", "home/sensor/watering_needed"));
+    }
+
+    #[test]
+    fn test_covers() {
+        assert!(covers("settings/#", "settings/home/+/interval"));
+        assert!(covers("home/+/watering_needed", "home/sensor/watering_needed"));
+        assert!(!covers("home/+/watering_needed", "home/#"));
+        assert!(!covers("home/sensor/+", "home/+/watering_needed"));
+        assert!(!covers("#", "$SYS/#"));
+    }
+
+    #[test]
+    fn test_roles() {
+        let config = config();
+        assert!(config.allows_publish(Role::Sensor, "home/sensor/watering_needed"));
+        assert!(!config.allows_publish(Role::Sensor, "home/watering/watering_needed"));
+        assert!(!config.allows_publish(Role::Sensor, "settings/home/sensor/interval"));
+        assert!(config.allows_subscribe(Role::Sensor, "$share/sensors/settings/home/sensor/#"));
+        // roles without rules are not allowed anything
+        assert!(!config.allows_publish(Role::Valve, "home/watering/watering_needed"));
+    }
+
+    #[test]
+    fn test_rules_follow_the_prefix() {
+        let config = AclConfig { prefix: "garden-a".to_string(), ..config() };
+        assert!(config.validate().is_ok());
+        assert!(config.allows_subscribe(Role::Sensor, "settings/garden-a/sensor/#"));
+        assert!(!config.allows_subscribe(Role::Sensor, "settings/home/sensor/#"));
+
+        let wildcard = AclConfig { prefix: "+".to_string(), ..config.clone() };
+        assert!(wildcard.validate().is_err());
+        let mut unknown = config;
+        unknown.roles.get_mut(&Role::Sensor).unwrap().publish = vec!["{prefx}/sensor/+".to_string()];
+        assert!(unknown.validate().is_err());
+    }
+
+    #[test]
+    fn test_denials_are_counted() {
+        let access = AccessControl::new(config(), PathBuf::from("/does/not/exist.toml"));
+        assert!(access.check_publish("sensor-1", Some(Role::Sensor), "home/sensor/watering_needed"));
+        assert!(!access.check_publish("sensor-1", Some(Role::Sensor), "home/watering/watering_needed"));
+        assert!(!access.check_subscribe("sensor-1", None, "settings/home/sensor/#"));
+
+        assert_eq!(access.denials(), Denials { publish: 1, subscribe: 1 });
+    }
+}
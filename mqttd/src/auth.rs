@@ -0,0 +1,325 @@
+use std::{ collections::BTreeMap, fmt, io, num::NonZeroU32, path::{ Path, PathBuf }, sync::Arc };
+
+use base64::{ engine::general_purpose::{ STANDARD, URL_SAFE_NO_PAD }, Engine };
+use ring::{ pbkdf2, rand::{ SecureRandom, SystemRandom } };
+use rumqttd::Config;
+use serde::{ Deserialize, Serialize };
+
+use crate::{ acl::Role, config_dirs };
+
+/// File name of the credential store in the standard locations
+pub const CREDENTIALS_FILE: &str = "credentials.toml";
+
+/// Prefix of the stored password hashes, identifies the algorithm
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+/// PBKDF2 iterations for new hashes, stored with the hash so it can be raised later
+const HASH_ITERATIONS: u32 = 100_000;
+/// Length of the random salt in bytes
+const SALT_LEN: usize = 16;
+/// Length of generated passwords in random bytes (before encoding)
+const PASSWORD_LEN: usize = 18;
+
+/// Locate the credential store
+/// An explicit path always wins, otherwise the directory of the configuration file (`base`),
+/// the working directory and the standard configuration directories are searched
+pub fn locate_credentials(path: Option<&Path>, base: Option<&Path>) -> Option<PathBuf> {
+    if let Some(path) = path {
+        return Some(path.to_path_buf());
+    }
+
+    base
+        .map(|dir| dir.join(CREDENTIALS_FILE))
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from(CREDENTIALS_FILE)))
+        .chain(config_dirs().into_iter().map(|dir| dir.join(CREDENTIALS_FILE)))
+        .find(|path| path.is_file())
+}
+
+/// Where a new credential store is created if none exists yet
+pub fn default_credentials_path() -> PathBuf {
+    config_dirs()
+        .into_iter()
+        .next()
+        .unwrap_or_default()
+        .join(CREDENTIALS_FILE)
+}
+
+/// Reason a connection was not authenticated
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// No device with the username is registered
+    UnknownDevice,
+    /// The password does not match the stored hash
+    WrongPassword,
+    /// The credential store could not be read
+    Store(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::UnknownDevice => write!(f, "unknown device"),
+            AuthError::WrongPassword => write!(f, "wrong password"),
+            AuthError::Store(error) => write!(f, "credential store unavailable: {}", error),
+        }
+    }
+}
+
+/// Stored credentials of a device
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Device {
+    /// Salted password hash in the format `pbkdf2-sha256$<iterations>$<salt>$<hash>`
+    pub hash: String,
+    /// Role deciding which topics the device may use, devices without one are not allowed any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+}
+
+/// Credentials of all devices allowed to connect, keyed by username
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Credentials {
+    #[serde(default)]
+    devices: BTreeMap<String, Device>,
+}
+
+impl Credentials {
+    /// Read the credential store, a missing file is an empty store
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Write the credential store, it is only readable by the owner
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let content = toml::to_string_pretty(self).map_err(io::Error::other)?;
+        std::fs::write(path, content)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+
+    /// Add a device or replace its password and role
+    pub fn set(&mut self, username: &str, password: &str, role: Role) {
+        self.devices.insert(username.to_string(), Device {
+            hash: hash_password(password),
+            role: Some(role),
+        });
+    }
+
+    /// Remove a device, returns whether it was registered
+    pub fn remove(&mut self, username: &str) -> bool {
+        self.devices.remove(username).is_some()
+    }
+
+    /// Usernames of all registered devices
+    pub fn devices(&self) -> impl Iterator<Item = &str> {
+        self.devices.keys().map(String::as_str)
+    }
+
+    /// The role of a registered device
+    pub fn role(&self, username: &str) -> Option<Role> {
+        self.devices.get(username).and_then(|device| device.role)
+    }
+
+    /// Check the username and password of a connecting client
+    pub fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let device = self.devices.get(username).ok_or(AuthError::UnknownDevice)?;
+        if verify_password(&device.hash, password) {
+            Ok(())
+        } else {
+            Err(AuthError::WrongPassword)
+        }
+    }
+}
+
+/// Generate a random password for a new device
+pub fn generate_password() -> String {
+    let mut bytes = [0u8; PASSWORD_LEN];
+    SystemRandom::new().fill(&mut bytes).expect("Failed to generate a random password");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Require every client to log in with credentials from the store
+/// The store is read on every connection attempt, so devices can be added or removed
+/// without restarting the broker
+pub fn require_authentication(config: &mut Config, path: PathBuf) {
+    let path = Arc::new(path);
+    let servers = [&mut config.v4, &mut config.v5, &mut config.ws]
+        .into_iter()
+        .flatten()
+        .flat_map(|servers| servers.values_mut());
+
+    for server in servers {
+        let path = path.clone();
+        let listener = server.name.clone();
+        server.connections.external_auth = Some(
+            Arc::new(move |client_id: String, username: String, password: String| {
+                let result = Credentials::load(&path)
+                    .map_err(|error| AuthError::Store(error.to_string()))
+                    .and_then(|credentials| credentials.authenticate(&username, &password));
+
+                match result {
+                    Ok(()) => true,
+                    Err(reason) => {
+                        tracing::warn!(
+                            "Rejected client '{}' with username '{}' on listener '{}': {}",
+                            client_id,
+                            username,
+                            listener,
+                            reason
+                        );
+                        false
+                    }
+                }
+            })
+        );
+    }
+}
+
+/// Require authentication with the located credential store
+/// Without a store the broker refuses to start, unless anonymous clients are allowed explicitly
+/// Returns the credential store clients authenticate against
+pub fn configure(
+    config: &mut Config,
+    path: Option<&Path>,
+    base: Option<&Path>,
+    allow_anonymous: bool
+) -> io::Result<Option<PathBuf>> {
+    enable(config, locate_credentials(path, base), allow_anonymous)
+}
+
+/// Require authentication with the credential store, or fail unless anonymous clients are allowed
+fn enable(config: &mut Config, path: Option<PathBuf>, allow_anonymous: bool) -> io::Result<Option<PathBuf>> {
+    match path {
+        Some(path) => {
+            tracing::info!("Authenticating clients with credentials from '{}'", path.display());
+            require_authentication(config, path.clone());
+            Ok(Some(path))
+        }
+        None if allow_anonymous => {
+            tracing::warn!("No credential store found, any client can connect without authentication");
+            Ok(None)
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no credential store found, register a device or allow anonymous clients explicitly"
+        )),
+    }
+}
+
+/// Hash the password with a new random salt
+fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new().fill(&mut salt).expect("Failed to generate a random salt");
+
+    let mut hash = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+    let iterations = NonZeroU32::new(HASH_ITERATIONS).unwrap();
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+
+    format!("{}${}${}${}", HASH_SCHEME, HASH_ITERATIONS, STANDARD.encode(salt), STANDARD.encode(hash))
+}
+
+/// Compare the password to a stored hash in constant time
+/// Malformed hashes never match
+fn verify_password(stored: &str, password: &str) -> bool {
+    let mut parts = stored.split('$');
+    let (Some(HASH_SCHEME), Some(iterations), Some(salt), Some(hash), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return false;
+    };
+
+    let Some(iterations) = iterations.parse().ok().and_then(NonZeroU32::new) else {
+        return false;
+    };
+    let (Ok(salt), Ok(hash)) = (STANDARD.decode(salt), STANDARD.decode(hash)) else {
+        return false;
+    };
+
+    pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &hash).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authenticate() {
+        let mut credentials = Credentials::default();
+        credentials.set("sensor-1", "secret", Role::Sensor);
+
+        assert_eq!(credentials.authenticate("sensor-1", "secret"), Ok(()));
+        assert_eq!(credentials.authenticate("sensor-1", "guess"), Err(AuthError::WrongPassword));
+        assert_eq!(credentials.authenticate("sensor-2", "secret"), Err(AuthError::UnknownDevice));
+    }
+
+    #[test]
+    fn test_hashes_are_salted() {
+        let first = hash_password("secret");
+        let second = hash_password("secret");
+
+        assert_ne!(first, second);
+        assert!(!first.contains("secret"));
+        assert!(verify_password(&first, "secret") && verify_password(&second, "secret"));
+        assert!(!verify_password("pbkdf2-sha256$0$AA==$AA==", "secret"));
+    }
+
+    #[test]
+    fn test_credentials_next_to_the_configuration() {
+        let dir = std::env::temp_dir().join("mqttd-test-credentials-base");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(CREDENTIALS_FILE), "").unwrap();
+
+        assert_eq!(locate_credentials(None, Some(&dir)), Some(dir.join(CREDENTIALS_FILE)));
+        let explicit = Path::new("other.toml");
+        assert_eq!(locate_credentials(Some(explicit), Some(&dir)).as_deref(), Some(explicit));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_fails_closed_without_credentials() {
+        let mut config = crate::default_config();
+        assert_eq!(enable(&mut config, None, false).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(enable(&mut config, None, true).unwrap(), None);
+
+        let path = PathBuf::from(CREDENTIALS_FILE);
+        assert_eq!(enable(&mut config, Some(path.clone()), false).unwrap(), Some(path));
+        let server = config.v4.as_ref().unwrap().values().next().unwrap();
+        assert!(server.connections.external_auth.is_some());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join("mqttd-test-credentials/credentials.toml");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(Credentials::load(&path).unwrap().devices().count(), 0);
+
+        let mut credentials = Credentials::default();
+        credentials.set("hub", "secret", Role::Hub);
+        credentials.set("valve", "other", Role::Valve);
+        assert!(credentials.remove("valve"));
+        credentials.save(&path).unwrap();
+
+        let loaded = Credentials::load(&path).unwrap();
+        assert_eq!(loaded.devices().collect::<Vec<_>>(), vec!["hub"]);
+        assert!(loaded.authenticate("hub", "secret").is_ok());
+        assert_eq!(loaded.role("hub"), Some(Role::Hub));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use std::{ io, path::{ Path, PathBuf } };
+
+use rcgen::{
+    BasicConstraints,
+    Certificate,
+    CertificateParams,
+    DistinguishedName,
+    DnType,
+    ExtendedKeyUsagePurpose,
+    IsCa,
+    KeyPair,
+    KeyUsagePurpose,
+};
+
+/// File name (without extension) of the certificate authority
+pub const CA_NAME: &str = "ca";
+/// File name (without extension) of the broker certificate
+pub const SERVER_NAME: &str = "server";
+
+/// Location of a certificate and its private key on disk
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertificateFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl CertificateFiles {
+    /// The files used for a certificate called `name` in the given directory
+    pub fn new(dir: &Path, name: &str) -> Self {
+        Self {
+            cert: dir.join(format!("{}.pem", name)),
+            key: dir.join(format!("{}.key", name)),
+        }
+    }
+
+    /// Write the PEM encoded certificate and key, the key is only readable by the owner
+    fn write(&self, cert: &Certificate, key: &KeyPair) -> io::Result<()> {
+        std::fs::write(&self.cert, cert.pem())?;
+        std::fs::write(&self.key, key.serialize_pem())?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&self.key, std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+}
+
+/// Self-signed certificate authority to issue the broker and device certificates
+/// Clients pin this CA instead of trusting the system roots
+pub struct CertificateAuthority {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl CertificateAuthority {
+    /// Create a new certificate authority
+    pub fn generate() -> io::Result<Self> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = distinguished_name("TerraTap CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+
+        let key = KeyPair::generate().map_err(io::Error::other)?;
+        let cert = params.self_signed(&key).map_err(io::Error::other)?;
+        Ok(Self { cert, key })
+    }
+
+    /// Load the certificate authority from the directory or create it if there is none
+    /// Reusing the authority allows to add device certificates later on
+    pub fn load_or_generate(dir: &Path) -> io::Result<Self> {
+        let files = CertificateFiles::new(dir, CA_NAME);
+        if !files.cert.is_file() || !files.key.is_file() {
+            let authority = Self::generate()?;
+            std::fs::create_dir_all(dir)?;
+            files.write(&authority.cert, &authority.key)?;
+            return Ok(authority);
+        }
+
+        let key = KeyPair::from_pem(&std::fs::read_to_string(&files.key)?).map_err(io::Error::other)?;
+        let params = CertificateParams
+            ::from_ca_cert_pem(&std::fs::read_to_string(&files.cert)?)
+            .map_err(io::Error::other)?;
+        // signing again keeps subject and key, so certificates issued now chain to the stored CA
+        let cert = params.self_signed(&key).map_err(io::Error::other)?;
+        Ok(Self { cert, key })
+    }
+
+    /// The PEM encoded certificate of the authority
+    pub fn pem(&self) -> String {
+        self.cert.pem()
+    }
+
+    /// Issue the certificate for the broker, valid for the given host names and IP addresses
+    pub fn issue_server(&self, hosts: &[String], dir: &Path) -> io::Result<CertificateFiles> {
+        let mut params = CertificateParams::new(hosts.to_vec()).map_err(io::Error::other)?;
+        params.distinguished_name = distinguished_name("mqttd");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        self.issue(params, dir, SERVER_NAME)
+    }
+
+    /// Issue a client certificate for a device
+    pub fn issue_device(&self, name: &str, dir: &Path) -> io::Result<CertificateFiles> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = distinguished_name(name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        self.issue(params, dir, name)
+    }
+
+    /// Sign the certificate with the authority and write it to the directory
+    fn issue(&self, params: CertificateParams, dir: &Path, name: &str) -> io::Result<CertificateFiles> {
+        let key = KeyPair::generate().map_err(io::Error::other)?;
+        let cert = params.signed_by(&key, &self.cert, &self.key).map_err(io::Error::other)?;
+
+        let files = CertificateFiles::new(dir, name);
+        std::fs::create_dir_all(dir)?;
+        files.write(&cert, &key)?;
+        Ok(files)
+    }
+}
+
+/// Distinguished name with only a common name
+/// No organization is set, rumqttd would treat it as tenant of the connection
+fn distinguished_name(common_name: &str) -> DistinguishedName {
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, common_name);
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issue_server_and_device() {
+        let dir = std::env::temp_dir().join("mqttd-test-certs-issue");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let authority = CertificateAuthority::load_or_generate(&dir).unwrap();
+        let server = authority.issue_server(&["localhost".to_string()], &dir).unwrap();
+        let device = authority.issue_device("sensor-1", &dir).unwrap();
+
+        assert!(dir.join("ca.pem").is_file());
+        assert!(server.cert.is_file() && server.key.is_file());
+        assert_eq!(device, CertificateFiles::new(&dir, "sensor-1"));
+        assert!(device.cert.is_file() && device.key.is_file());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_existing_authority() {
+        let dir = std::env::temp_dir().join("mqttd-test-certs-load");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let created = CertificateAuthority::load_or_generate(&dir).unwrap();
+        let loaded = CertificateAuthority::load_or_generate(&dir).unwrap();
+        assert_eq!(created.key.serialize_pem(), loaded.key.serialize_pem());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,459 @@
+use std::{ collections::HashMap, net::SocketAddr, path::{ Path, PathBuf } };
+
+use rumqttd::{ Config, ServerSettings, TlsConfig };
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+
+use crate::{ acl::AclConfig, metrics::StatisticsConfig };
+
+/// Name of the v4 listener added by `--tls-listen`
+pub const TLS_LISTENER: &str = "tls";
+
+/// Name of the websocket listener added by `--ws-listen`
+pub const WS_LISTENER: &str = "ws";
+
+/// Default broker configuration, embedded so the binaries run without any files
+pub const DEFAULT_CONFIG: &str = include_str!("../rumqttd.toml");
+
+/// File name of the broker configuration in the standard locations
+pub const CONFIG_FILE: &str = "rumqttd.toml";
+
+/// Standard directories that hold the TerraTap configuration files
+/// The user configuration directory takes precedence over the system wide one
+pub fn config_dirs() -> Vec<PathBuf> {
+    let user_dir = std::env
+        ::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    user_dir
+        .into_iter()
+        .map(|dir| dir.join("terratap"))
+        .chain(std::iter::once(PathBuf::from("/etc/terratap")))
+        .collect()
+}
+
+/// Locate the broker configuration file
+/// An explicit path always wins, otherwise the working directory (also when running from source)
+/// and the standard configuration directories are searched
+pub fn locate_config(path: Option<&Path>) -> Option<PathBuf> {
+    if let Some(path) = path {
+        return Some(path.to_path_buf());
+    }
+
+    [PathBuf::from("mqttd").join(CONFIG_FILE), PathBuf::from(CONFIG_FILE)]
+        .into_iter()
+        .chain(config_dirs().into_iter().map(|dir| dir.join(CONFIG_FILE)))
+        .find(|path| path.is_file())
+}
+
+/// Settings of `mqttd` that rumqttd does not know about
+/// They are read from the same files as the rumqttd configuration
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extensions {
+    /// Topics the devices may use by role, only enforced if clients have to authenticate
+    #[serde(default)]
+    pub acl: AclConfig,
+    /// Settings shared by all websocket listeners (the `[ws.<name>]` sections)
+    #[serde(default)]
+    pub websocket: WebsocketConfig,
+    /// What is kept across restarts of the broker
+    #[serde(default)]
+    pub persistence: PersistenceConfig,
+    /// Statistics of the broker under `sys/broker/...` and for Prometheus
+    /// (`[metrics]` is a section of rumqttd)
+    #[serde(default)]
+    pub statistics: StatisticsConfig,
+}
+
+/// Files the broker state is written to, nothing is kept if not set
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistenceConfig {
+    /// Store of the retained messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retained: Option<PathBuf>,
+}
+
+/// Settings of the websocket listeners
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebsocketConfig {
+    /// HTTP path clients have to open the websocket on
+    pub path: String,
+}
+
+impl Default for WebsocketConfig {
+    fn default() -> Self {
+        Self { path: "/mqtt".to_string() }
+    }
+}
+
+/// The embedded default configuration
+pub fn default_config() -> Config {
+    load_layers(None).expect("embedded default configuration is valid")
+}
+
+/// Load the broker configuration
+/// The located configuration file is layered on top of the embedded default,
+/// so it only has to contain the values that differ
+pub fn load_config(path: Option<&Path>) -> Result<Config, config::ConfigError> {
+    let path = locate_config(path);
+    match &path {
+        Some(path) => tracing::info!("Loading broker configuration from '{}'", path.display()),
+        None => tracing::info!("Using embedded default broker configuration"),
+    }
+    load_layers(path.as_deref())
+}
+
+/// The extensions of the embedded default configuration
+pub fn default_extensions() -> Extensions {
+    load_layers(None).expect("embedded default configuration is valid")
+}
+
+/// Load the extensions from the same files as the broker configuration
+pub fn load_extensions(path: Option<&Path>) -> Result<Extensions, config::ConfigError> {
+    load_layers(locate_config(path).as_deref())
+}
+
+/// Merge the embedded default and an optional configuration file
+fn load_layers<T: DeserializeOwned>(path: Option<&Path>) -> Result<T, config::ConfigError> {
+    let mut builder = config::Config
+        ::builder()
+        .add_source(config::File::from_str(DEFAULT_CONFIG, config::FileFormat::Toml));
+    if let Some(path) = path {
+        builder = builder.add_source(config::File::from(path));
+    }
+
+    builder.build()?.try_deserialize()
+}
+
+/// Overrides for the most common settings, applied on top of the configuration files
+/// Each can be given as command line flag or environment variable, the flag wins
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct Overrides {
+    /// Listen address of the MQTT v4 listener
+    #[arg(long, env = "MQTTD_V4_LISTEN")]
+    pub v4_listen: Option<SocketAddr>,
+    /// Listen address of the MQTT v5 listener
+    #[arg(long, env = "MQTTD_V5_LISTEN")]
+    pub v5_listen: Option<SocketAddr>,
+    /// Maximum number of concurrent connections
+    #[arg(long, env = "MQTTD_MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+    /// Listen address of an additional MQTT v4 listener secured with TLS
+    #[arg(long, env = "MQTTD_TLS_LISTEN", requires_all = ["tls_cert", "tls_key"])]
+    pub tls_listen: Option<SocketAddr>,
+    /// PEM encoded certificate of the broker for the TLS listener
+    #[arg(long, env = "MQTTD_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM encoded private key of the broker for the TLS listener
+    #[arg(long, env = "MQTTD_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// Listen address of the MQTT over websocket listener
+    #[arg(long, env = "MQTTD_WS_LISTEN")]
+    pub ws_listen: Option<SocketAddr>,
+    /// HTTP path of the websocket listeners
+    #[arg(long, env = "MQTTD_WS_PATH")]
+    pub ws_path: Option<String>,
+    /// Secure the websocket listener with the TLS certificate and key
+    #[arg(long, env = "MQTTD_WS_TLS", requires_all = ["ws_listen", "tls_cert", "tls_key"])]
+    pub ws_tls: bool,
+    /// Credential store clients authenticate against
+    /// Defaults to the first `credentials.toml` found next to the configuration file, in the working
+    /// directory or the standard configuration directories, without one the broker does not start
+    #[arg(long, env = "MQTTD_CREDENTIALS")]
+    pub credentials: Option<PathBuf>,
+    /// Let clients connect without authentication if no credential store is found
+    #[arg(long, env = "MQTTD_ALLOW_ANONYMOUS")]
+    pub allow_anonymous: bool,
+    /// Topic prefix of the hub the access control rules are applied to, `home` by default
+    #[arg(long, env = "MQTTD_TOPIC_PREFIX")]
+    pub topic_prefix: Option<String>,
+    /// Root of the settings topics of the hub for the access control rules, `settings` by default
+    #[arg(long, env = "MQTTD_SETTINGS_TOPIC")]
+    pub settings_topic: Option<String>,
+    /// File the retained messages are kept in across restarts
+    #[arg(long, env = "MQTTD_RETAINED_STORE")]
+    pub retained_store: Option<PathBuf>,
+    /// Publish the broker statistics under `sys/broker`
+    #[arg(long, env = "MQTTD_METRICS")]
+    pub metrics: bool,
+    /// Listen address of the Prometheus endpoint, enables the statistics
+    #[arg(long, env = "MQTTD_METRICS_LISTEN")]
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+impl Overrides {
+    /// Apply the overrides to the configuration
+    /// Listen addresses replace the one of the first listener (ordered by name) of that version
+    pub fn apply(&self, config: &mut Config) -> Result<(), String> {
+        if let Some(addr) = self.v4_listen {
+            apply_listen(&mut config.v4, "v4", addr);
+        }
+        if let Some(addr) = self.v5_listen {
+            apply_listen(&mut config.v5, "v5", addr);
+        }
+        if let Some(max_connections) = self.max_connections {
+            config.router.max_connections = max_connections;
+        }
+        if let Some(addr) = self.tls_listen {
+            let tls = self.tls().ok_or("the TLS listener needs a certificate and a key")?;
+            add_listener(config, "v4", TLS_LISTENER, addr, Some(tls))?;
+        }
+        if let Some(addr) = self.ws_listen {
+            let tls = match self.ws_tls {
+                true => Some(self.tls().ok_or("the websocket listener needs a certificate and a key for TLS")?),
+                false => None,
+            };
+            add_listener(config, "ws", WS_LISTENER, addr, tls)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the overrides for the settings rumqttd does not know about
+    pub fn apply_extensions(&self, extensions: &mut Extensions) {
+        if let Some(path) = &self.ws_path {
+            extensions.websocket.path = path.clone();
+        }
+        if let Some(prefix) = &self.topic_prefix {
+            extensions.acl.prefix = prefix.clone();
+        }
+        if let Some(settings) = &self.settings_topic {
+            extensions.acl.settings = settings.clone();
+        }
+        if let Some(path) = &self.retained_store {
+            extensions.persistence.retained = Some(path.clone());
+        }
+        if self.metrics {
+            extensions.statistics.enabled = true;
+        }
+        if let Some(addr) = self.metrics_listen {
+            extensions.statistics.enabled = true;
+            extensions.statistics.listen = Some(addr);
+        }
+    }
+
+    /// TLS settings with the certificate and key, if both are given
+    fn tls(&self) -> Option<TlsConfig> {
+        let (cert, key) = (self.tls_cert.as_ref()?, self.tls_key.as_ref()?);
+        Some(TlsConfig::Rustls {
+            capath: None,
+            certpath: cert.display().to_string(),
+            keypath: key.display().to_string(),
+        })
+    }
+}
+
+/// Add a listener, the connection settings are taken from the first v4 listener
+fn add_listener(
+    config: &mut Config,
+    kind: &str,
+    name: &str,
+    addr: SocketAddr,
+    tls: Option<TlsConfig>
+) -> Result<(), String> {
+    let template = config.v4
+        .iter()
+        .flat_map(|servers| servers.iter())
+        .min_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, server)| server.clone())
+        .ok_or_else(|| format!("the {} listener needs a v4 listener to copy the connection settings from", name))?;
+
+    let servers = match kind {
+        "ws" => &mut config.ws,
+        _ => &mut config.v4,
+    };
+    servers.get_or_insert_with(HashMap::new).insert(name.to_string(), ServerSettings {
+        name: format!("{}-{}", kind, name),
+        listen: addr,
+        tls,
+        ..template
+    });
+    Ok(())
+}
+
+/// Replace the listen address of the first listener
+fn apply_listen(
+    servers: &mut Option<HashMap<String, ServerSettings>>,
+    kind: &str,
+    addr: SocketAddr
+) {
+    let server = servers
+        .iter_mut()
+        .flat_map(|servers| servers.iter_mut())
+        .min_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, server)| server);
+
+    match server {
+        Some(server) => {
+            server.listen = addr;
+        }
+        None => tracing::warn!("No {} listener configured, ignoring listen address {}", kind, addr),
+    }
+}
+
+/// Check the configuration for mistakes rumqttd would only notice while running
+pub fn validate(config: &Config) -> Result<(), String> {
+    let servers = [&config.v4, &config.v5, &config.ws]
+        .into_iter()
+        .flatten()
+        .flat_map(|servers| servers.values())
+        .collect::<Vec<_>>();
+
+    if servers.is_empty() {
+        return Err("at least one v4, v5 or ws listener has to be configured".to_string());
+    }
+    if config.router.max_connections == 0 {
+        return Err("router.max_connections has to be greater than 0".to_string());
+    }
+
+    let missing_tls_files = servers
+        .iter()
+        .find(|server| server.tls.as_ref().is_some_and(|tls| !tls.validate_paths()));
+    if let Some(server) = missing_tls_files {
+        return Err(format!("certificate or key of listener '{}' not found", server.name));
+    }
+
+    let mut addrs = servers.iter().map(|server| server.listen).collect::<Vec<_>>();
+    addrs.sort();
+    if let Some(pair) = addrs.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(format!("more than one listener uses {}", pair[0]));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Role;
+
+    #[test]
+    fn test_default_config_is_valid() {
+        assert!(validate(&default_config()).is_ok());
+    }
+
+    #[test]
+    fn test_load_config_layers_file_on_default() {
+        let path = std::env::temp_dir().join("mqttd-test-layered.toml");
+        std::fs::write(&path, "[v4.1]\nlisten = \"127.0.0.1:2883\"\n").unwrap();
+
+        let config = load_config(Some(&path)).unwrap();
+        let v4 = config.v4.unwrap();
+        assert_eq!(v4["1"].listen, "127.0.0.1:2883".parse().unwrap());
+        // everything else is taken from the default
+        assert_eq!(v4["1"].name, "v4-1");
+        assert_eq!(config.router.max_connections, 10010);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_extensions_layers_file_on_default() {
+        let path = std::env::temp_dir().join("mqttd-test-acl.toml");
+        std::fs::write(&path, "[acl.roles.sensor]\npublish = [\"garden/+\"]\n").unwrap();
+
+        let extensions = load_extensions(Some(&path)).unwrap();
+        assert_eq!(extensions.websocket.path, "/mqtt");
+        assert_eq!(extensions.persistence.retained, None);
+        let acl = extensions.acl;
+        assert!(acl.allows_publish(Role::Sensor, "garden/moisture"));
+        assert!(!acl.allows_publish(Role::Sensor, "home/sensor/watering_needed"));
+        // the subscriptions and the other roles are taken from the default
+        assert!(acl.allows_subscribe(Role::Sensor, "settings/home/sensor/#"));
+        assert!(acl.allows_publish(Role::Hub, "settings/home/sensor/interval"));
+
+        // the default rules follow the prefix
+        let mut extensions = default_extensions();
+        Overrides { topic_prefix: Some("garden-a".to_string()), ..Default::default() }.apply_extensions(&mut extensions);
+        assert!(extensions.acl.validate().is_ok());
+        assert!(extensions.acl.allows_publish(Role::Valve, "garden-a/watering/watering_needed"));
+        assert!(!extensions.acl.allows_publish(Role::Valve, "home/watering/watering_needed"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_config_missing_explicit_file() {
+        assert!(load_config(Some(Path::new("/does/not/exist.toml"))).is_err());
+    }
+
+    #[test]
+    fn test_locate_config_prefers_explicit_path() {
+        let path = Path::new("/does/not/exist.toml");
+        assert_eq!(locate_config(Some(path)), Some(path.to_path_buf()));
+    }
+
+    #[test]
+    fn test_overrides_apply() {
+        let mut config = default_config();
+        let overrides = Overrides {
+            v4_listen: Some("127.0.0.1:2883".parse().unwrap()),
+            max_connections: Some(5),
+            ..Default::default()
+        };
+        overrides.apply(&mut config).unwrap();
+
+        assert_eq!(config.v4.unwrap()["1"].listen, "127.0.0.1:2883".parse().unwrap());
+        assert_eq!(config.v5.unwrap()["1"].listen, "0.0.0.0:1884".parse().unwrap());
+        assert_eq!(config.router.max_connections, 5);
+    }
+
+    #[test]
+    fn test_validate_duplicate_listen_address() {
+        let mut config = default_config();
+        Overrides {
+            v5_listen: Some("0.0.0.0:1883".parse().unwrap()),
+            ..Default::default()
+        }
+            .apply(&mut config)
+            .unwrap();
+
+        assert_eq!(validate(&config).unwrap_err(), "more than one listener uses 0.0.0.0:1883");
+    }
+
+    #[test]
+    fn test_overrides_add_tls_listener() {
+        let mut config = default_config();
+        Overrides {
+            tls_listen: Some("0.0.0.0:8883".parse().unwrap()),
+            tls_cert: Some(PathBuf::from("certs/server.pem")),
+            tls_key: Some(PathBuf::from("certs/server.key")),
+            ..Default::default()
+        }
+            .apply(&mut config)
+            .unwrap();
+
+        let v4 = config.v4.as_ref().unwrap();
+        assert_eq!(v4[TLS_LISTENER].listen, "0.0.0.0:8883".parse().unwrap());
+        assert_eq!(v4[TLS_LISTENER].connections.max_payload_size, v4["1"].connections.max_payload_size);
+        // the certificate files do not exist
+        assert_eq!(validate(&config).unwrap_err(), "certificate or key of listener 'v4-tls' not found");
+    }
+
+    #[test]
+    fn test_overrides_add_websocket_listener() {
+        let mut config = default_config();
+        let mut extensions = default_extensions();
+        let overrides = Overrides {
+            ws_listen: Some("0.0.0.0:8083".parse().unwrap()),
+            ws_path: Some("/dashboard".to_string()),
+            ..Default::default()
+        };
+        overrides.apply(&mut config).unwrap();
+        overrides.apply_extensions(&mut extensions);
+
+        let ws = config.ws.as_ref().unwrap();
+        assert_eq!(ws[WS_LISTENER].listen, "0.0.0.0:8083".parse().unwrap());
+        assert!(ws[WS_LISTENER].tls.is_none());
+        assert_eq!(extensions.websocket.path, "/dashboard");
+        assert!(validate(&config).is_ok());
+    }
+
+    #[test]
+    fn test_validate_no_listeners() {
+        let mut config = default_config();
+        config.v4 = None;
+        config.v5 = None;
+
+        assert!(validate(&config).is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{ IpAddr, Ipv4Addr, SocketAddr, TcpListener },
+    path::PathBuf,
+    sync::Arc,
+    thread::{ self, JoinHandle },
+    time::{ Duration, Instant },
+};
+
+use acl::AccessControl;
+use metrics::{ StatisticsConfig, Stats };
+use proxy::{ Inspection, Route };
+use retain::RetainedStore;
+use rumqttd::{ local::LinkTx, Broker, TlsConfig };
+use tokio::net::{ TcpSocket, TcpStream };
+use tokio_rustls::TlsAcceptor;
+
+pub mod acl;
+pub mod auth;
+pub mod certs;
+mod configuration;
+pub mod metrics;
+mod proxy;
+pub mod retain;
+
+pub use configuration::{
+    config_dirs,
+    default_config,
+    default_extensions,
+    load_config,
+    load_extensions,
+    locate_config,
+    validate,
+    Extensions,
+    Overrides,
+    PersistenceConfig,
+    WebsocketConfig,
+    CONFIG_FILE,
+    DEFAULT_CONFIG,
+    TLS_LISTENER,
+    WS_LISTENER,
+};
+pub use rumqttd::Config;
+
+/// Interval in which the listeners are probed while waiting for the broker
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// MQTT protocol version spoken on a listener
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Protocol {
+    V4,
+    V5,
+}
+
+/// Handle to a broker running in the current process
+pub struct BrokerHandle {
+    listeners: Vec<(Protocol, SocketAddr)>,
+    /// Internal listeners of the broker behind the proxy
+    upstreams: Vec<(Protocol, SocketAddr)>,
+    access: Option<Arc<AccessControl>>,
+    retained: Option<Arc<RetainedStore>>,
+    stats: Option<Arc<Stats>>,
+    thread: JoinHandle<()>,
+}
+
+impl BrokerHandle {
+    /// The addresses the broker accepts MQTT connections on
+    pub fn listeners(&self) -> &[(Protocol, SocketAddr)] {
+        &self.listeners
+    }
+
+    /// Wait until every listener of the broker accepts connections
+    pub async fn ready(&self, timeout: Duration) -> io::Result<()> {
+        // the proxy only forwards once the broker behind it is listening
+        for (_, addr) in self.upstreams.iter().chain(&self.listeners) {
+            wait_until_ready(*addr, timeout).await?;
+        }
+        Ok(())
+    }
+
+    /// The access control, if clients are restricted to the topics of their role
+    pub fn access_control(&self) -> Option<&Arc<AccessControl>> {
+        self.access.as_ref()
+    }
+
+    /// The store of the retained messages, if they are kept across restarts
+    pub fn retained_store(&self) -> Option<&Arc<RetainedStore>> {
+        self.retained.as_ref()
+    }
+
+    /// The statistics, if they are collected
+    pub fn stats(&self) -> Option<&Arc<Stats>> {
+        self.stats.as_ref()
+    }
+
+    /// Block until the broker stops, which only happens if it fails
+    pub fn join(self) {
+        let _ = self.thread.join();
+    }
+}
+
+/// Start the broker in the current process
+/// rumqttd runs its listeners on dedicated threads and never returns from `start`,
+/// so the broker is driven from its own thread instead of blocking the async runtime
+/// With a credential store the devices are restricted to the topics of their role,
+/// authentication itself has to be enabled with `auth::configure` beforehand
+/// Retained messages are restored from the store of the extensions before clients can connect
+/// With metrics enabled the statistics are published and served once the broker runs
+pub fn start(
+    mut config: Config,
+    extensions: Extensions,
+    credentials: Option<PathBuf>
+) -> io::Result<BrokerHandle> {
+    if extensions.statistics.enabled && extensions.statistics.prefix.starts_with('$') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the statistics prefix can not start with '$'"));
+    }
+    if credentials.is_some() {
+        extensions.acl.validate().map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    }
+    let listeners = listeners(&config);
+    let access = credentials.map(|path| Arc::new(AccessControl::new(extensions.acl, path)));
+    let retained = match extensions.persistence.retained {
+        Some(path) => Some(Arc::new(RetainedStore::open(path)?)),
+        // the statistics count the retained messages
+        None if extensions.statistics.enabled => Some(Arc::new(RetainedStore::in_memory())),
+        None => None,
+    };
+    let stats = retained
+        .clone()
+        .filter(|_| extensions.statistics.enabled)
+        .map(|retained| Arc::new(Stats::new(retained)));
+    let inspection = Inspection { access: access.clone(), retained: retained.clone(), stats: stats.clone() };
+
+    // the websocket listeners are added as internal v4 listeners, which do not need checks of their own
+    let mut routes = Vec::new();
+    if !inspection.is_empty() {
+        routes.extend(route_mqtt(&mut config)?);
+    }
+    routes.extend(route_websockets(&mut config, &extensions.websocket)?);
+    let upstreams = routes
+        .iter()
+        .map(|(_, route)| (route.protocol, route.upstream))
+        .collect();
+
+    // the router runs as soon as the broker is created, the listeners only once it is started
+    let mut broker = Broker::new(config);
+    let status = match &stats {
+        Some(_) => Some(broker.link("mqttd-status").map_err(io::Error::other)?.0),
+        None => None,
+    };
+    let metrics_listener = match extensions.statistics.listen.filter(|_| stats.is_some()) {
+        Some(addr) => Some(bind(addr)?),
+        None => None,
+    };
+
+    let thread = thread::Builder::new()
+        .name("mqttd".to_string())
+        .spawn({
+            let retained = retained.clone();
+            move || {
+                if let Some(retained) = retained {
+                    if let Err(error) = retained.restore(&broker) {
+                        tracing::error!("Failed to restore the retained messages: {}", error);
+                    }
+                }
+                if let Err(error) = broker.start() {
+                    tracing::error!("MQTT broker stopped: {}", error);
+                }
+            }
+        })?;
+
+    if !routes.is_empty() {
+        start_proxy(routes, inspection, extensions.statistics, status, metrics_listener)?;
+    }
+
+    Ok(BrokerHandle { listeners, upstreams, access, retained, stats, thread })
+}
+
+/// Move the v4 and v5 listeners of the broker to free loopback ports and route the configured
+/// addresses to them through the access control and the retained store, TLS is terminated by the proxy
+fn route_mqtt(config: &mut Config) -> io::Result<Vec<(TcpListener, Route)>> {
+    let mut routes = Vec::new();
+    for (protocol, servers) in [(Protocol::V4, &mut config.v4), (Protocol::V5, &mut config.v5)] {
+        for server in servers.iter_mut().flat_map(|servers| servers.values_mut()) {
+            let listener = bind(server.listen)?;
+            let tls = proxy_tls(server.tls.take())?;
+            let reservation = internal_addr()?;
+            server.listen = reservation.local_addr()?;
+            routes.push((listener, Route {
+                upstream: server.listen,
+                protocol,
+                tls,
+                websocket: None,
+                max_packet_size: server.connections.max_payload_size,
+                _reservation: reservation,
+            }));
+        }
+    }
+    Ok(routes)
+}
+
+/// Replace the websocket listeners of rumqttd by internal v4 listeners and route the configured
+/// addresses to them, the proxy handles TLS and the websocket handshake including the path
+fn route_websockets(config: &mut Config, websocket: &WebsocketConfig) -> io::Result<Vec<(TcpListener, Route)>> {
+    let mut routes = Vec::new();
+    for (name, mut server) in config.ws.take().unwrap_or_default() {
+        let listener = bind(server.listen)?;
+        let tls = proxy_tls(server.tls.take())?;
+        let reservation = internal_addr()?;
+        server.listen = reservation.local_addr()?;
+        routes.push((listener, Route {
+            upstream: server.listen,
+            protocol: Protocol::V4,
+            tls,
+            websocket: Some(websocket.path.clone()),
+            max_packet_size: server.connections.max_payload_size,
+            _reservation: reservation,
+        }));
+        config.v4.get_or_insert_with(HashMap::new).insert(format!("ws-{}", name), server);
+    }
+    Ok(routes)
+}
+
+/// Bind a public listener of the proxy
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    TcpListener::bind(addr).map_err(|error| io::Error::new(error.kind(), format!("{}: {}", addr, error)))
+}
+
+/// Reserve a free loopback port for a listener of the broker behind the proxy
+/// The socket is bound without listening, so the port is not handed out to anyone else,
+/// while rumqttd can still bind it because both sockets allow reusing the address
+fn internal_addr() -> io::Result<TcpSocket> {
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))?;
+    Ok(socket)
+}
+
+/// TLS acceptor of the proxy for the TLS settings of a listener
+fn proxy_tls(tls: Option<TlsConfig>) -> io::Result<Option<TlsAcceptor>> {
+    match tls {
+        Some(TlsConfig::Rustls { certpath, keypath, .. }) => Ok(Some(proxy::tls_acceptor(&certpath, &keypath)?)),
+        Some(TlsConfig::NativeTls { .. }) => {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "native TLS is not supported by the proxy"))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Run the proxy on its own thread, next to the broker
+/// The statistics it collects are published over the status link and served on the metrics listener
+fn start_proxy(
+    routes: Vec<(TcpListener, Route)>,
+    inspection: Inspection,
+    statistics: StatisticsConfig,
+    status: Option<LinkTx>,
+    metrics_listener: Option<TcpListener>
+) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().thread_name("mqttd-proxy").build()?;
+
+    thread::Builder::new()
+        .name("mqttd-proxy".to_string())
+        .spawn(move || {
+            runtime.block_on(async move {
+                if let (Some(stats), Some(status)) = (inspection.stats.clone(), status) {
+                    tokio::spawn(metrics::publish(stats, statistics, status));
+                }
+                if let (Some(stats), Some(listener)) = (inspection.stats.clone(), metrics_listener) {
+                    tokio::spawn(async move {
+                        if let Err(error) = metrics::serve(listener, stats).await {
+                            tracing::error!("Metrics endpoint stopped: {}", error);
+                        }
+                    });
+                }
+                let tasks = routes
+                    .into_iter()
+                    .map(|(listener, route)| tokio::spawn(proxy::serve(listener, route, inspection.clone())))
+                    .collect::<Vec<_>>();
+                for task in tasks {
+                    if let Ok(Err(error)) = task.await {
+                        tracing::error!("Proxy stopped: {}", error);
+                    }
+                }
+            })
+        })?;
+    Ok(())
+}
+
+/// Collect the addresses of the plain MQTT listeners in the configuration
+/// TLS listeners are left out, they are started together with the plain ones
+/// Listeners bound to all interfaces are reachable over the loopback interface
+pub fn listeners(config: &Config) -> Vec<(Protocol, SocketAddr)> {
+    [(Protocol::V4, &config.v4), (Protocol::V5, &config.v5)]
+        .into_iter()
+        .filter_map(|(protocol, servers)| servers.as_ref().map(|servers| (protocol, servers)))
+        .flat_map(|(protocol, servers)| servers.values().map(move |server| (protocol, server)))
+        .filter(|(_, server)| server.tls.is_none())
+        .map(|(protocol, server)| {
+            let mut addr = server.listen;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            }
+            (protocol, addr)
+        })
+        .collect()
+}
+
+/// Wait until a broker accepts connections on the given address
+/// Only the TCP connection is probed, so it works the same with and without authentication
+pub async fn wait_until_ready(addr: SocketAddr, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match TcpStream::connect(addr).await.map(drop) {
+            Ok(()) => {
+                return Ok(());
+            }
+            Err(error) if Instant::now() >= deadline => {
+                return Err(
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("broker not ready on {} after {:?}: {}", addr, timeout, error)
+                    )
+                );
+            }
+            Err(_) => tokio::time::sleep(READY_POLL_INTERVAL).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Find a port that is currently not in use
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    /// The default configuration with the v4 listener moved to the given port
+    fn config_on_port(port: u16) -> Config {
+        let mut config = default_config();
+        config.v5 = None;
+        for server in config.v4.as_mut().unwrap().values_mut() {
+            server.listen = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        }
+        config
+    }
+
+    #[test]
+    fn test_listeners_use_loopback_for_unspecified() {
+        let config = default_config();
+        let mut listeners = listeners(&config);
+        listeners.sort();
+
+        assert_eq!(
+            listeners,
+            vec![
+                (Protocol::V4, "127.0.0.1:1883".parse().unwrap()),
+                (Protocol::V5, "127.0.0.1:1884".parse().unwrap())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_start_becomes_ready() {
+        let port = free_port();
+        let broker = start(config_on_port(port), default_extensions(), None).unwrap();
+
+        broker.ready(Duration::from_secs(5)).await.unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        assert!(TcpStream::connect(addr).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_is_served_on_its_path() {
+        let (port, ws_port) = (free_port(), free_port());
+        let mut config = config_on_port(port);
+        let overrides = Overrides {
+            ws_listen: Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), ws_port)),
+            ..Default::default()
+        };
+        overrides.apply(&mut config).unwrap();
+        let broker = start(config, default_extensions(), None).unwrap();
+        broker.ready(Duration::from_secs(5)).await.unwrap();
+
+        let url = format!("ws://127.0.0.1:{}", ws_port);
+        assert!(async_tungstenite::tokio::connect_async(format!("{}/mqtt", url)).await.is_ok());
+        assert!(async_tungstenite::tokio::connect_async(format!("{}/other", url)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_ready_times_out() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), free_port());
+        let result = wait_until_ready(addr, Duration::from_millis(200)).await;
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
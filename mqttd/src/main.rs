@@ -1,9 +1,75 @@
-use rumqttd::{ Broker, Config };
+use std::{ path::PathBuf, time::Duration };
+
+use clap::{ Parser, Subcommand };
+use mqttd::certs::CertificateAuthority;
 use tracing::span;
 use tracing_subscriber::{ EnvFilter, FmtSubscriber };
 
+/// MQTT broker for TerraTap
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Path to the rumqttd configuration file
+    /// Defaults to the first `rumqttd.toml` found in the working directory or the standard
+    /// configuration directories, or the embedded default configuration
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Validate and print the merged configuration without starting the broker
+    #[arg(long)]
+    check: bool,
+
+    #[command(flatten)]
+    overrides: mqttd::Overrides,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate a self-signed CA with certificates for the broker and the devices
+    /// An existing CA in the output directory is reused
+    Certs {
+        /// Directory the certificates are written to
+        #[arg(long, default_value = "certs")]
+        out: PathBuf,
+        /// Host names and IP addresses the broker certificate is valid for
+        #[arg(long = "host", default_values = ["localhost", "127.0.0.1"])]
+        hosts: Vec<String>,
+        /// Name of a device to issue a client certificate for, can be repeated
+        #[arg(long = "device")]
+        devices: Vec<String>,
+    },
+}
+
+/// Generate the certificates and print where they were written to
+fn generate_certs(out: PathBuf, hosts: Vec<String>, devices: Vec<String>) -> std::io::Result<()> {
+    let authority = CertificateAuthority::load_or_generate(&out)?;
+    println!("CA: {}", out.join(format!("{}.pem", mqttd::certs::CA_NAME)).display());
+
+    let server = authority.issue_server(&hosts, &out)?;
+    println!("Broker: {} {}", server.cert.display(), server.key.display());
+
+    for device in devices {
+        let files = authority.issue_device(&device, &out)?;
+        println!("Device '{}': {} {}", device, files.cert.display(), files.key.display());
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+
+    if let Some(Command::Certs { out, hosts, devices }) = args.command {
+        if let Err(error) = generate_certs(out, hosts, devices) {
+            eprintln!("Failed to generate certificates: {}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Use the RUST_LOG environment variable to set the log level
     let filter = EnvFilter::new(std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()));
     let subscriber = FmtSubscriber::builder()
@@ -12,6 +78,8 @@ async fn main() {
         .with_target(true)
         .with_thread_ids(false)
         .with_thread_names(false)
+        // keep stdout free for the output of `--check`
+        .with_writer(std::io::stderr)
         .finish();
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
@@ -20,21 +88,54 @@ async fn main() {
     let broker_span = span!(tracing::Level::INFO, "mqtt-server");
     let _ = broker_span.enter();
 
-    // Configure the broker using the rumqttd configuration file
-    // TODO: this should be handled differently to support for various configuration sources
-    //      and to allow for more flexible configuration (not running from source)
-    let raw_mqtt_config = config::Config
-        ::builder()
-        .add_source(config::File::with_name("mqttd/rumqttd.toml"))
-        .build()
-        .unwrap();
-    let mqtt_config: Config = raw_mqtt_config.try_deserialize().unwrap();
-
-    // Create the final instance of the broker and start it
-    let mut broker = Broker::new(mqtt_config);
-    tokio
-        ::spawn(async move {
-            broker.start().unwrap();
-        }).await
-        .unwrap();
+    // Configure the broker from the embedded default, the configuration file,
+    // environment variables and command line flags (in this order)
+    let loaded = mqttd::load_config(args.config.as_deref()).and_then(|config| {
+        Ok((config, mqttd::load_extensions(args.config.as_deref())?))
+    });
+    let (mut mqtt_config, mut extensions) = match loaded {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!("Invalid broker configuration: {}", error);
+            std::process::exit(1);
+        }
+    };
+    if let Err(error) = args.overrides.apply(&mut mqtt_config).and_then(|_| mqttd::validate(&mqtt_config)) {
+        eprintln!("Invalid broker configuration: {}", error);
+        std::process::exit(1);
+    }
+    args.overrides.apply_extensions(&mut extensions);
+    if let Err(error) = extensions.acl.validate() {
+        eprintln!("Invalid broker configuration: {}", error);
+        std::process::exit(1);
+    }
+    // the roles of the devices are kept in the credential store, so access control needs authentication
+    // the credential store next to the configuration file is preferred over the working directory
+    let config_dir = mqttd::locate_config(args.config.as_deref()).and_then(|path| path.parent().map(PathBuf::from));
+    let credentials = mqttd::auth::configure(
+        &mut mqtt_config,
+        args.overrides.credentials.as_deref(),
+        config_dir.as_deref(),
+        args.overrides.allow_anonymous
+    );
+    let credentials = match credentials {
+        Ok(credentials) => credentials,
+        Err(error) => {
+            eprintln!("Invalid broker configuration: {}", error);
+            std::process::exit(1);
+        }
+    };
+    if args.check {
+        print!("{}", toml::to_string_pretty(&mqtt_config).expect("Failed to serialize configuration"));
+        print!("\n{}", toml::to_string_pretty(&extensions).expect("Failed to serialize configuration"));
+        return;
+    }
+
+    // Start the broker and keep running until it stops
+    let broker = mqttd::start(mqtt_config, extensions, credentials).expect("Failed to start broker");
+    match broker.ready(Duration::from_secs(10)).await {
+        Ok(()) => tracing::info!("MQTT broker listening on {:?}", broker.listeners()),
+        Err(error) => tracing::error!("MQTT broker did not become ready: {}", error),
+    }
+    tokio::task::spawn_blocking(move || broker.join()).await.unwrap();
 }
@@ -0,0 +1,236 @@
+use std::{
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    sync::{ atomic::{ AtomicU64, Ordering }, Arc },
+    time::{ Duration, Instant },
+};
+
+use bytes::Bytes;
+use rumqttd::{ local::LinkTx, protocol::{ Packet, Publish } };
+use serde::{ Deserialize, Serialize };
+use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, net::TcpListener };
+
+use crate::retain::RetainedStore;
+
+/// The `[statistics]` section of the broker configuration
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatisticsConfig {
+    /// Collect the statistics and publish them under the prefix
+    #[serde(default)]
+    pub enabled: bool,
+    /// Seconds between two publications of the statistics
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Topic prefix of the statistics, like `$SYS/broker` of other brokers
+    /// rumqttd does not allow subscriptions to topics starting with `$`
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Listen address of the Prometheus endpoint, not served if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen: Option<SocketAddr>,
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+fn default_prefix() -> String {
+    "sys/broker".to_string()
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self {
+        Self { enabled: false, interval: default_interval(), prefix: default_prefix(), listen: None }
+    }
+}
+
+/// Statistics of a running broker, collected by the proxy in front of it
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    connections: AtomicU64,
+    total_connections: AtomicU64,
+    subscriptions: AtomicU64,
+    received: AtomicU64,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    retained: Arc<RetainedStore>,
+}
+
+/// The statistics at one point in time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub uptime: u64,
+    /// Clients connected right now
+    pub connections: u64,
+    /// Clients connected since the start
+    pub total_connections: u64,
+    /// Subscriptions of the connected clients
+    pub subscriptions: u64,
+    /// Messages published by the clients
+    pub received: u64,
+    /// Messages delivered to the clients
+    pub sent: u64,
+    /// Packets of the clients that were not forwarded, because they were denied or malformed
+    pub dropped: u64,
+    pub retained: u64,
+}
+
+impl Stats {
+    /// The retained messages are counted in the store
+    pub fn new(retained: Arc<RetainedStore>) -> Self {
+        Self {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            subscriptions: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            retained,
+        }
+    }
+
+    pub(crate) fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A client disconnected, its subscriptions end with the connection
+    pub(crate) fn disconnected(&self, subscriptions: usize) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+        self.subscriptions.fetch_sub(subscriptions as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn subscribed(&self) {
+        self.subscriptions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn unsubscribed(&self) {
+        self.subscriptions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The current statistics
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            uptime: self.started.elapsed().as_secs(),
+            connections: self.connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            subscriptions: self.subscriptions.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            retained: self.retained.len() as u64,
+        }
+    }
+}
+
+impl Snapshot {
+    /// Topic (below the prefix), Prometheus name, type and help of each value
+    fn values(&self) -> [(&'static str, &'static str, &'static str, &'static str, u64); 8] {
+        [
+            ("uptime", "mqttd_uptime_seconds", "counter", "Seconds since the broker started", self.uptime),
+            ("clients/connected", "mqttd_clients_connected", "gauge", "Clients connected right now", self.connections),
+            ("clients/total", "mqttd_clients_total", "counter", "Clients connected since the start", self.total_connections),
+            ("subscriptions/count", "mqttd_subscriptions", "gauge", "Subscriptions of the connected clients", self.subscriptions),
+            ("messages/received", "mqttd_messages_received_total", "counter", "Messages published by the clients", self.received),
+            ("messages/sent", "mqttd_messages_sent_total", "counter", "Messages delivered to the clients", self.sent),
+            ("packets/dropped", "mqttd_packets_dropped_total", "counter", "Packets of the clients that were denied or malformed", self.dropped),
+            ("retained/count", "mqttd_retained_messages", "gauge", "Retained messages", self.retained),
+        ]
+    }
+
+    /// Topics and payloads of the status messages
+    pub fn topics(&self, prefix: &str) -> Vec<(String, String)> {
+        self.values()
+            .into_iter()
+            .map(|(topic, _, _, _, value)| (format!("{}/{}", prefix, topic), value.to_string()))
+            .collect()
+    }
+
+    /// The statistics in the Prometheus text format
+    pub fn prometheus(&self) -> String {
+        let mut text = String::new();
+        for (_, name, kind, help, value) in self.values() {
+            let _ = writeln!(text, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+        }
+        text
+    }
+}
+
+/// Publish the statistics as retained messages every interval, until the broker stops
+pub(crate) async fn publish(stats: Arc<Stats>, config: StatisticsConfig, mut link: LinkTx) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        interval.tick().await;
+        for (topic, payload) in stats.snapshot().topics(&config.prefix) {
+            let publish = Publish::new(Bytes::from(topic), Bytes::from(payload), true);
+            if let Err(error) = link.send(Packet::Publish(publish, None)).await {
+                tracing::error!("Failed to publish the broker statistics: {}", error);
+                return;
+            }
+        }
+    }
+}
+
+/// Serve the statistics to Prometheus, every request is answered with them
+pub(crate) async fn serve(listener: std::net::TcpListener, stats: Arc<Stats>) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+
+    loop {
+        let (mut stream, addr) = listener.accept().await?;
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            // the request itself does not matter, the headers are read so the client is not reset
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await;
+
+            let body = stats.snapshot().prometheus();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(error) = stream.write_all(response.as_bytes()).await {
+                tracing::debug!("Failed to serve the metrics to {}: {}", addr, error);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_formats() {
+        let stats = Stats::new(Arc::new(RetainedStore::in_memory()));
+        stats.connected();
+        stats.subscribed();
+        stats.subscribed();
+        stats.received();
+        stats.disconnected(1);
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.connections, snapshot.total_connections, snapshot.subscriptions), (0, 1, 1));
+
+        let topics = snapshot.topics("sys/broker");
+        assert!(topics.contains(&("sys/broker/messages/received".to_string(), "1".to_string())));
+        let text = snapshot.prometheus();
+        assert!(text.contains("# TYPE mqttd_clients_connected gauge\nmqttd_clients_connected 0\n"));
+        assert!(text.contains("mqttd_messages_received_total 1\n"));
+    }
+}
@@ -0,0 +1,492 @@
+use std::{ collections::{ HashMap, HashSet }, fs::File, io::{ self, BufReader }, net::SocketAddr, sync::{ Arc, Mutex } };
+
+use async_tungstenite::tungstenite::{
+    handshake::server::{ Callback, ErrorResponse, Request, Response },
+    http::{ HeaderValue, StatusCode },
+};
+use bytes::{ Bytes, BytesMut };
+use rumqttd::protocol::{
+    self,
+    v4::{ self, PacketType, V4 },
+    v5::V5,
+    ConnectReturnCode,
+    Packet,
+    Protocol as _,
+    SubAck,
+    Subscribe,
+    SubscribeReasonCode,
+};
+use tokio::{
+    io::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt },
+    net::{ TcpListener, TcpSocket, TcpStream },
+    sync::Mutex as AsyncMutex,
+};
+use tokio_rustls::{ rustls::ServerConfig, TlsAcceptor };
+use ws_stream_tungstenite::WsStream;
+
+use crate::{ acl::{ AccessControl, Role }, metrics::Stats, retain::RetainedStore, Protocol };
+
+/// Where the connections of a public listener are forwarded to
+pub(crate) struct Route {
+    pub upstream: SocketAddr,
+    pub protocol: Protocol,
+    pub tls: Option<TlsAcceptor>,
+    /// HTTP path of the websocket, for MQTT over websocket
+    pub websocket: Option<String>,
+    pub max_packet_size: usize,
+    /// Keeps the port of the upstream reserved while the proxy runs, see `crate::internal_addr`
+    pub _reservation: TcpSocket,
+}
+
+/// What the proxy does with the packets of the clients
+#[derive(Clone, Default)]
+pub(crate) struct Inspection {
+    /// Restrict the clients to the topics of their role
+    pub access: Option<Arc<AccessControl>>,
+    /// Keep the retained messages across restarts
+    pub retained: Option<Arc<RetainedStore>>,
+    /// Count the connections and messages
+    pub stats: Option<Arc<Stats>>,
+}
+
+impl Inspection {
+    /// Count a packet of a client that is not forwarded
+    fn dropped(&self) {
+        if let Some(stats) = &self.stats {
+            stats.dropped();
+        }
+    }
+
+    /// Whether the packets can be passed through unseen
+    pub fn is_empty(&self) -> bool {
+        self.access.is_none() && self.retained.is_none() && self.stats.is_none()
+    }
+}
+
+/// TLS acceptor for the PEM encoded certificate chain and private key
+pub(crate) fn tls_acceptor(certpath: &str, keypath: &str) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile
+        ::certs(&mut BufReader::new(File::open(certpath)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile
+        ::private_key(&mut BufReader::new(File::open(keypath)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no private key in '{}'", keypath)))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accept connections on the listener until the process exits
+pub(crate) async fn serve(
+    listener: std::net::TcpListener,
+    route: Route,
+    inspection: Inspection
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let (route, inspection) = (Arc::new(route), Arc::new(inspection));
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let (route, inspection) = (route.clone(), inspection.clone());
+        tokio::spawn(async move {
+            let result = match &route.tls {
+                Some(acceptor) => {
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => unwrap_websocket(stream, &route, &inspection).await,
+                        Err(error) => Err(error),
+                    }
+                }
+                None => unwrap_websocket(stream, &route, &inspection).await,
+            };
+            if let Err(error) = result {
+                tracing::debug!("Connection from {} closed: {}", addr, error);
+            }
+        });
+    }
+}
+
+/// Complete the websocket handshake if the route is a websocket, then connect to the broker
+async fn unwrap_websocket<S>(client: S, route: &Route, inspection: &Inspection) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Send + Unpin
+{
+    let Some(path) = &route.websocket else {
+        return connect(client, route, inspection).await;
+    };
+
+    let websocket = async_tungstenite::tokio
+        ::accept_hdr_async(client, Handshake { path })
+        .await
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    connect(WsStream::new(websocket), route, inspection).await
+}
+
+/// Checks the path of a websocket request and accepts the MQTT subprotocol
+struct Handshake<'a> {
+    path: &'a str,
+}
+
+impl Callback for Handshake<'_> {
+    fn on_request(self, request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+        if request.uri().path() != self.path {
+            let mut error = ErrorResponse::new(Some(format!("no MQTT websocket at {}", request.uri().path())));
+            *error.status_mut() = StatusCode::NOT_FOUND;
+            return Err(error);
+        }
+
+        let mqtt = request
+            .headers()
+            .get_all("sec-websocket-protocol")
+            .iter()
+            .filter_map(|protocols| protocols.to_str().ok())
+            .any(|protocols| protocols.split(',').any(|protocol| protocol.trim() == "mqtt"));
+        if mqtt {
+            response.headers_mut().insert("sec-websocket-protocol", HeaderValue::from_static("mqtt"));
+        }
+        Ok(response)
+    }
+}
+
+/// Forward a client connection to the broker
+/// Packets from the broker are passed through, when inspecting the packets from the client are
+/// checked and recorded first
+async fn connect<S>(client: S, route: &Route, inspection: &Inspection) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut upstream = TcpStream::connect(route.upstream).await?;
+    if inspection.is_empty() {
+        let mut client = client;
+        return tokio::io::copy_bidirectional(&mut client, &mut upstream).await.map(|_| ());
+    }
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = upstream.into_split();
+    let mut session = Session::default();
+    let mut buffer = BytesMut::with_capacity(4096);
+
+    // nothing but the CONNECT is forwarded before the broker accepted the login,
+    // so publications of rejected clients are never recorded
+    let Some(connect) = read_frame(&mut client_read, &mut buffer, route.max_packet_size).await? else {
+        return Ok(());
+    };
+    if let Verdict::Deny = login(parse(connect.clone(), route)?, &mut session, inspection) {
+        inspection.dropped();
+        return Ok(());
+    }
+    upstream_write.write_all(&connect).await?;
+
+    let mut upstream_buffer = BytesMut::with_capacity(64);
+    let Some(connack) = read_frame(&mut upstream_read, &mut upstream_buffer, route.max_packet_size).await? else {
+        return Ok(());
+    };
+    client_write.write_all(&connack).await?;
+    match parse(connack, route)? {
+        Packet::ConnAck(connack, _) if connack.code == ConnectReturnCode::Success => {}
+        _ => {
+            return Ok(());
+        }
+    }
+
+    let stats = inspection.stats.as_deref();
+    if let Some(stats) = stats {
+        stats.connected();
+    }
+    // the proxy answers refused subscriptions itself, so both directions write to the client
+    let client_write = AsyncMutex::new(client_write);
+    let refused = Mutex::new(HashMap::new());
+    // either side closing ends the connection
+    let result = tokio::select! {
+        result = relay(&mut upstream_read, &client_write, upstream_buffer, &refused, route, inspection) => result,
+        result = forward(&mut client_read, &mut upstream_write, &client_write, buffer, &mut session, &refused, route, inspection) => result,
+    };
+    if let Some(stats) = stats {
+        stats.disconnected(session.filters.len());
+    }
+    result
+}
+
+/// What is known about the client of a connection
+#[derive(Default)]
+struct Session {
+    client_id: String,
+    role: Option<Role>,
+    /// MQTT v5 topic aliases set by the client
+    aliases: HashMap<u16, String>,
+    /// Filters the client is subscribed to
+    filters: HashSet<String>,
+}
+
+/// Read the next complete packet, `None` once the stream is closed
+async fn read_frame<R>(stream: &mut R, buffer: &mut BytesMut, max_packet_size: usize) -> io::Result<Option<BytesMut>>
+    where R: AsyncRead + Unpin
+{
+    loop {
+        // the fixed header is the same for v4 and v5
+        match v4::check(buffer.iter(), max_packet_size) {
+            Ok(header) => {
+                return Ok(Some(buffer.split_to(header.frame_length())));
+            }
+            Err(protocol::Error::InsufficientBytes(_)) => {
+                if stream.read_buf(buffer).await? == 0 {
+                    return Ok(None);
+                }
+            }
+            Err(error) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string()));
+            }
+        }
+    }
+}
+
+/// Parse a complete packet in the protocol of the route
+fn parse(mut frame: BytesMut, route: &Route) -> io::Result<Packet> {
+    match route.protocol {
+        Protocol::V4 => V4.read_mut(&mut frame, route.max_packet_size),
+        Protocol::V5 => V5.read_mut(&mut frame, route.max_packet_size),
+    }.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))
+}
+
+/// Encode a packet in the protocol of the route
+fn encode(packet: Packet, route: &Route) -> io::Result<BytesMut> {
+    let mut frame = BytesMut::new();
+    match route.protocol {
+        Protocol::V4 => V4.write(packet, &mut frame),
+        Protocol::V5 => V5.write(packet, &mut frame),
+    }.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+    Ok(frame)
+}
+
+/// Positions of the refused filters of the subscriptions in flight, by packet id
+type Refused = Mutex<HashMap<u16, Vec<usize>>>;
+
+/// Pass the packets of the broker to the client, counting the delivered messages
+/// The acknowledgements of subscriptions with refused filters get the failure codes of those filters
+/// The buffer holds what was already read from the broker
+async fn relay<R, W>(
+    upstream: &mut R,
+    client: &AsyncMutex<W>,
+    mut buffer: BytesMut,
+    refused: &Refused,
+    route: &Route,
+    inspection: &Inspection
+) -> io::Result<()>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin
+{
+    let stats = inspection.stats.as_deref();
+    if stats.is_none() && inspection.access.is_none() {
+        // nothing to count or complete, the client is not written to by anyone else
+        let mut client = client.lock().await;
+        client.write_all(&buffer).await?;
+        return tokio::io::copy(upstream, &mut *client).await.map(|_| ());
+    }
+
+    // the broker only sends packets the client accepts, their size is not limited here
+    while let Some(mut frame) = read_frame(upstream, &mut buffer, usize::MAX).await? {
+        match frame[0] >> 4 {
+            kind if kind == PacketType::Publish as u8 => {
+                if let Some(stats) = stats {
+                    stats.sent();
+                }
+            }
+            kind if kind == PacketType::SubAck as u8 => {
+                if let Packet::SubAck(mut suback, properties) = parse(frame.clone(), route)? {
+                    let positions = refused.lock().unwrap().remove(&suback.pkid);
+                    if let Some(positions) = positions {
+                        for position in positions {
+                            let position = position.min(suback.return_codes.len());
+                            suback.return_codes.insert(position, failure(route));
+                        }
+                        frame = encode(Packet::SubAck(suback, properties), route)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        client.lock().await.write_all(&frame).await?;
+    }
+    Ok(())
+}
+
+/// Return code of a refused subscription filter
+fn failure(route: &Route) -> SubscribeReasonCode {
+    match route.protocol {
+        Protocol::V4 => SubscribeReasonCode::Failure,
+        Protocol::V5 => SubscribeReasonCode::NotAuthorized,
+    }
+}
+
+/// Forward the packets of the client that pass the access control
+/// A denied publication closes the connection, as MQTT requires for unauthorized publications,
+/// refused subscription filters are acknowledged with a failure code instead
+#[allow(clippy::too_many_arguments)]
+async fn forward<R, U, W>(
+    client: &mut R,
+    upstream: &mut U,
+    client_write: &AsyncMutex<W>,
+    mut buffer: BytesMut,
+    session: &mut Session,
+    refused: &Refused,
+    route: &Route,
+    inspection: &Inspection
+) -> io::Result<()>
+    where R: AsyncRead + Unpin, U: AsyncWrite + Unpin, W: AsyncWrite + Unpin
+{
+    loop {
+        let packet = match read_frame(client, &mut buffer, route.max_packet_size).await {
+            Ok(Some(frame)) => parse(frame.clone(), route).map(|packet| (frame, packet)),
+            Ok(None) => {
+                return Ok(());
+            }
+            Err(error) => Err(error),
+        };
+        let (frame, packet) = match packet {
+            Ok(packet) => packet,
+            Err(error) => {
+                // malformed packets end the connection
+                if error.kind() == io::ErrorKind::InvalidData {
+                    inspection.dropped();
+                }
+                return Err(error);
+            }
+        };
+
+        if let Packet::Subscribe(mut subscribe, properties) = packet {
+            let positions = refuse(&mut subscribe, session, inspection);
+            if positions.is_empty() {
+                upstream.write_all(&frame).await?;
+                subscribed(&subscribe, session, inspection);
+            } else if subscribe.filters.is_empty() {
+                // the broker does not accept a subscription without filters, so the proxy answers
+                inspection.dropped();
+                let return_codes = positions.iter().map(|_| failure(route)).collect();
+                let suback = SubAck { pkid: subscribe.pkid, return_codes };
+                client_write.lock().await.write_all(&encode(Packet::SubAck(suback, None), route)?).await?;
+            } else {
+                refused.lock().unwrap().insert(subscribe.pkid, positions);
+                upstream.write_all(&encode(Packet::Subscribe(subscribe.clone(), properties), route)?).await?;
+                subscribed(&subscribe, session, inspection);
+            }
+            continue;
+        }
+        let verdict = check(packet, session, inspection);
+        if let Verdict::Deny = verdict {
+            inspection.dropped();
+            return Ok(());
+        }
+        if let Verdict::Violation = verdict {
+            tracing::warn!("Closing the connection of '{}': CONNECT after the handshake", session.client_id);
+            inspection.dropped();
+            return Ok(());
+        }
+        upstream.write_all(&frame).await?;
+        if let Verdict::Publish { topic, payload, retain } = verdict {
+            published(&topic, &payload, retain, inspection).await;
+        }
+    }
+}
+
+/// What happens to a packet of the client
+enum Verdict {
+    /// The packet is dropped and the connection closed
+    Deny,
+    /// The packet breaks the protocol, it is dropped and the connection closed
+    Violation,
+    Forward,
+    /// A publication, counted and stored if retained once the broker got it
+    Publish { topic: String, payload: Bytes, retain: bool },
+}
+
+/// Count a forwarded publication and store it if it is retained
+async fn published(topic: &str, payload: &[u8], retain: bool, inspection: &Inspection) {
+    if let Some(stats) = &inspection.stats {
+        stats.received();
+    }
+    if let Some(retained) = inspection.retained.as_deref().filter(|_| retain) {
+        if let Err(error) = retained.record(topic, payload).await {
+            tracing::error!("Failed to store the retained message of '{}': {}", topic, error);
+        }
+    }
+}
+
+/// Remove the filters of the subscription the client may not subscribe to
+/// Returns their positions
+fn refuse(subscribe: &mut Subscribe, session: &Session, inspection: &Inspection) -> Vec<usize> {
+    let access = inspection.access.as_deref();
+    let mut positions = Vec::new();
+    let mut position = 0;
+    subscribe.filters.retain(|filter| {
+        let allowed = access.is_none_or(|access| access.check_subscribe(&session.client_id, session.role, &filter.path));
+        if !allowed {
+            positions.push(position);
+        }
+        position += 1;
+        allowed
+    });
+    positions
+}
+
+/// Count the new filters of a forwarded subscription
+fn subscribed(subscribe: &Subscribe, session: &mut Session, inspection: &Inspection) {
+    if let Some(stats) = &inspection.stats {
+        for filter in &subscribe.filters {
+            if session.filters.insert(filter.path.clone()) {
+                stats.subscribed();
+            }
+        }
+    }
+}
+
+/// Check the first packet of the client, remembering the client and its role
+/// Anything but a CONNECT is denied, the broker authenticates the login afterwards
+fn login(packet: Packet, session: &mut Session, inspection: &Inspection) -> Verdict {
+    let Packet::Connect(connect, _, will, _, login) = packet else {
+        return Verdict::Deny;
+    };
+    session.client_id = connect.client_id;
+    let Some(access) = inspection.access.as_deref() else {
+        return Verdict::Forward;
+    };
+    session.role = login.and_then(|login| access.role(&login.username));
+    let allowed = will.is_none_or(|will| {
+        access.check_publish(&session.client_id, session.role, &String::from_utf8_lossy(&will.topic))
+    });
+    if allowed { Verdict::Forward } else { Verdict::Deny }
+}
+
+/// Check a packet of the client after the handshake
+/// The role is only taken from the first CONNECT, see `login`, another one is a protocol violation
+/// the broker would ignore without authenticating it
+/// Publications are only counted and recorded once they were forwarded, see `published`
+fn check(packet: Packet, session: &mut Session, inspection: &Inspection) -> Verdict {
+    let access = inspection.access.as_deref();
+    match packet {
+        Packet::Connect(..) => Verdict::Violation,
+        Packet::Publish(publish, properties) => {
+            let mut topic = String::from_utf8_lossy(&publish.topic).into_owned();
+            if let Some(alias) = properties.and_then(|properties| properties.topic_alias) {
+                if topic.is_empty() {
+                    topic = session.aliases.get(&alias).cloned().unwrap_or_default();
+                } else {
+                    session.aliases.insert(alias, topic.clone());
+                }
+            }
+            if !access.is_none_or(|access| access.check_publish(&session.client_id, session.role, &topic)) {
+                return Verdict::Deny;
+            }
+            Verdict::Publish { topic, payload: publish.payload, retain: publish.retain }
+        }
+        Packet::Unsubscribe(unsubscribe, _) => {
+            if let Some(stats) = &inspection.stats {
+                for filter in unsubscribe.filters {
+                    if session.filters.remove(&filter) {
+                        stats.unsubscribed();
+                    }
+                }
+            }
+            Verdict::Forward
+        }
+        _ => Verdict::Forward,
+    }
+}
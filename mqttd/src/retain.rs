@@ -0,0 +1,153 @@
+use std::{ collections::BTreeMap, io, path::{ Path, PathBuf }, sync::Mutex };
+
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use bytes::Bytes;
+use rumqttd::{ protocol::{ Packet, Publish }, Broker };
+use serde::{ Deserialize, Serialize };
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Content of the store file
+/// The QoS is not kept, rumqttd delivers retained messages with the QoS of the subscription
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Messages {
+    /// Base64 encoded payloads by topic, payloads do not have to be text
+    #[serde(default)]
+    retained: BTreeMap<String, String>,
+}
+
+/// Retained messages of the broker, written to a file on every change so they survive restarts
+/// MQTT v5 properties like the message expiry are not kept
+#[derive(Debug)]
+pub struct RetainedStore {
+    /// Without a file the messages are only tracked, to count them
+    path: Option<PathBuf>,
+    messages: Mutex<Messages>,
+    /// Held while the file is written, so an older version never replaces a newer one
+    writing: AsyncMutex<()>,
+}
+
+impl RetainedStore {
+    /// Open the store, a missing file is an empty store
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let messages = match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Messages::default(),
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        Ok(Self { path: Some(path), messages: Mutex::new(messages), writing: AsyncMutex::new(()) })
+    }
+
+    /// A store that is not written to disk
+    pub fn in_memory() -> Self {
+        Self { path: None, messages: Mutex::new(Messages::default()), writing: AsyncMutex::new(()) }
+    }
+
+    /// Location of the store file
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Number of retained messages
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().retained.len()
+    }
+
+    /// Whether there are no retained messages
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The stored payloads by topic
+    pub fn messages(&self) -> BTreeMap<String, Vec<u8>> {
+        let messages = self.messages.lock().unwrap();
+        messages.retained
+            .iter()
+            .filter_map(|(topic, payload)| {
+                match STANDARD.decode(payload) {
+                    Ok(payload) => Some((topic.clone(), payload)),
+                    Err(_) => {
+                        tracing::warn!("Skipping the retained message of '{}', its payload is not valid base64", topic);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Record a retained publication, an empty payload removes the retained message of the topic
+    /// The file is only written if something changed, the hub republishes its settings on every connect
+    pub async fn record(&self, topic: &str, payload: &[u8]) -> io::Result<()> {
+        let changed = {
+            let mut messages = self.messages.lock().unwrap();
+            if payload.is_empty() {
+                messages.retained.remove(topic).is_some()
+            } else {
+                let payload = STANDARD.encode(payload);
+                messages.retained.insert(topic.to_string(), payload.clone()) != Some(payload)
+            }
+        };
+
+        if let Some(path) = self.path.as_deref().filter(|_| changed) {
+            let _writing = self.writing.lock().await;
+            // the messages are serialized after waiting, so the last writer saves the latest version
+            let content = toml::to_string_pretty(&*self.messages.lock().unwrap()).map_err(io::Error::other)?;
+            save(path, content).await?;
+        }
+        Ok(())
+    }
+
+    /// Publish the stored messages to the broker, before its listeners accept clients
+    /// the router handles them before any connection
+    pub fn restore(&self, broker: &Broker) -> io::Result<()> {
+        let messages = self.messages();
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let (mut link_tx, _link_rx) = broker.link("mqttd-retained").map_err(io::Error::other)?;
+        // local links only take QoS 0 publications, sending never waits for the router
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        for (topic, payload) in &messages {
+            let publish = Publish::new(Bytes::from(topic.clone()), Bytes::from(payload.clone()), true);
+            runtime.block_on(link_tx.send(Packet::Publish(publish, None))).map_err(io::Error::other)?;
+        }
+        tracing::info!("Restored {} retained messages", messages.len());
+        Ok(())
+    }
+
+}
+
+/// Replace the file, a crash while writing leaves the previous version
+async fn save(path: &Path, content: String) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let temporary = path.with_extension("tmp");
+    tokio::fs::write(&temporary, content).await?;
+    tokio::fs::rename(temporary, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_reopen() {
+        let path = std::env::temp_dir().join("mqttd-test-retained/retained.toml");
+        let _ = std::fs::remove_file(&path);
+
+        let store = RetainedStore::open(path.clone()).unwrap();
+        store.record("settings/home/sensor/interval", b"60").await.unwrap();
+        store.record("settings/home/watering/duration", b"\x00\xff").await.unwrap();
+        assert_eq!(RetainedStore::open(path.clone()).unwrap().messages()["settings/home/watering/duration"], b"\x00\xff");
+
+        store.record("settings/home/watering/duration", b"").await.unwrap();
+        let messages = RetainedStore::open(path.clone()).unwrap().messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages["settings/home/sensor/interval"], b"60");
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
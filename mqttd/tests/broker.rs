@@ -0,0 +1,299 @@
+//! Tests of the broker with a MQTT client connecting to it
+
+use std::{ net::TcpListener, path::{ Path, PathBuf }, time::Duration };
+
+use mqttd::{ acl::Role, auth::Credentials, certs::{ CertificateAuthority, CertificateFiles, CA_NAME } };
+use rumqttc::{ AsyncClient, Event, Incoming, MqttOptions, QoS, SubscribeFilter, SubscribeReasonCode, Transport };
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+
+/// Find a port that is currently not in use
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Broker configuration with only a v4 listener on the port
+fn plain_config(port: u16) -> mqttd::Config {
+    let mut config = mqttd::default_config();
+    config.v5 = None;
+    mqttd::Overrides {
+        v4_listen: Some(format!("127.0.0.1:{}", port).parse().unwrap()),
+        ..Default::default()
+    }
+        .apply(&mut config)
+        .unwrap();
+    config
+}
+
+/// Require authentication, the credential store is returned to start the broker with
+/// Registers the devices `hub` and `sensor-1`, both with the password `secret`
+fn secure(config: &mut mqttd::Config, path: &Path) -> PathBuf {
+    let mut credentials = Credentials::default();
+    credentials.set("hub", "secret", Role::Hub);
+    credentials.set("sensor-1", "secret", Role::Sensor);
+    credentials.save(path).unwrap();
+
+    mqttd::auth::require_authentication(config, path.to_path_buf());
+    path.to_path_buf()
+}
+
+/// Poll the event loop until the broker acknowledges the connection or an error occurs
+async fn connect(options: MqttOptions) -> Result<(), rumqttc::ConnectionError> {
+    let (_client, mut eventloop) = AsyncClient::new(options, 10);
+    loop {
+        if let Event::Incoming(Incoming::ConnAck(_)) = eventloop.poll().await? {
+            return Ok(());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_authenticated_connection() {
+    let path = std::env::temp_dir().join("mqttd-test-auth-credentials.toml");
+    let port = free_port();
+    let mut config = plain_config(port);
+    let credentials = secure(&mut config, &path);
+    let broker = mqttd::start(config, mqttd::default_extensions(), Some(credentials)).unwrap();
+    broker.ready(Duration::from_secs(5)).await.unwrap();
+
+    let login = |credentials: Option<(&str, &str)>| {
+        let mut options = MqttOptions::new("hub-auth-test", "127.0.0.1", port);
+        if let Some((username, password)) = credentials {
+            options.set_credentials(username, password);
+        }
+        options
+    };
+    let timeout = Duration::from_secs(5);
+    let accepted = tokio::time::timeout(timeout, connect(login(Some(("hub", "secret"))))).await;
+    assert!(matches!(accepted, Ok(Ok(()))));
+    let wrong_password = tokio::time::timeout(timeout, connect(login(Some(("hub", "guess"))))).await;
+    assert!(matches!(wrong_password, Ok(Err(_))));
+    let anonymous = tokio::time::timeout(timeout, connect(login(None))).await;
+    assert!(matches!(anonymous, Ok(Err(_))));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_tls_connection_through_access_control() {
+    let dir = std::env::temp_dir().join("mqttd-test-tls-acl");
+    let _ = std::fs::remove_dir_all(&dir);
+    let authority = CertificateAuthority::load_or_generate(&dir).unwrap();
+    let server = authority.issue_server(&["localhost".to_string()], &dir).unwrap();
+
+    let (plain_port, tls_port) = (free_port(), free_port());
+    let mut config = plain_config(plain_port);
+    mqttd::Overrides {
+        tls_listen: Some(format!("127.0.0.1:{}", tls_port).parse().unwrap()),
+        tls_cert: Some(server.cert),
+        tls_key: Some(server.key),
+        ..Default::default()
+    }
+        .apply(&mut config)
+        .unwrap();
+    mqttd::validate(&config).unwrap();
+    let credentials = secure(&mut config, &dir.join("credentials.toml"));
+    let broker = mqttd::start(config, mqttd::default_extensions(), Some(credentials)).unwrap();
+    broker.ready(Duration::from_secs(5)).await.unwrap();
+
+    let mut options = MqttOptions::new("hub-tls-acl-test", "localhost", tls_port);
+    options.set_credentials("hub", "secret");
+    let ca = std::fs::read(CertificateFiles::new(&dir, CA_NAME).cert).unwrap();
+    options.set_transport(Transport::tls(ca, None, None));
+    let result = tokio::time::timeout(Duration::from_secs(5), connect(options)).await;
+    assert!(matches!(result, Ok(Ok(()))));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_access_control_denies_valve_command_of_sensor() {
+    let path = std::env::temp_dir().join("mqttd-test-acl-credentials.toml");
+    let port = free_port();
+    let mut config = plain_config(port);
+    let credentials = secure(&mut config, &path);
+    let broker = mqttd::start(config, mqttd::default_extensions(), Some(credentials)).unwrap();
+    broker.ready(Duration::from_secs(5)).await.unwrap();
+
+    let mut options = MqttOptions::new("sensor-1", "127.0.0.1", port);
+    options.set_credentials("sensor-1", "secret");
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    client.subscribe("settings/home/sensor/#", QoS::AtLeastOnce).await.unwrap();
+    client.publish("home/sensor/watering_needed", QoS::AtLeastOnce, false, "true").await.unwrap();
+
+    // the allowed requests are acknowledged
+    let acknowledged = tokio::time::timeout(Duration::from_secs(5), async {
+        let (mut suback, mut puback) = (false, false);
+        while !(suback && puback) {
+            match eventloop.poll().await.unwrap() {
+                Event::Incoming(Incoming::SubAck(_)) => {
+                    suback = true;
+                }
+                Event::Incoming(Incoming::PubAck(_)) => {
+                    puback = true;
+                }
+                _ => {}
+            }
+        }
+    }).await;
+    assert!(acknowledged.is_ok());
+
+    // refused filters are acknowledged with a failure code, the allowed ones are subscribed
+    let mixed = vec![
+        SubscribeFilter::new("home/watering/#".to_string(), QoS::AtLeastOnce),
+        SubscribeFilter::new("settings/home/sensor/check_time".to_string(), QoS::AtLeastOnce),
+    ];
+    client.subscribe_many(mixed).await.unwrap();
+    client.subscribe("home/watering/start", QoS::AtLeastOnce).await.unwrap();
+    let return_codes = tokio::time::timeout(Duration::from_secs(5), async {
+        let mut return_codes = Vec::new();
+        while return_codes.len() < 2 {
+            if let Event::Incoming(Incoming::SubAck(suback)) = eventloop.poll().await.unwrap() {
+                return_codes.push(suback.return_codes);
+            }
+        }
+        return_codes
+    }).await.unwrap();
+    assert_eq!(return_codes, vec![
+        vec![SubscribeReasonCode::Failure, SubscribeReasonCode::Success(QoS::AtLeastOnce)],
+        vec![SubscribeReasonCode::Failure],
+    ]);
+
+    // a valve command closes the connection
+    client.publish("home/watering/watering_needed", QoS::AtLeastOnce, false, "true").await.unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        while eventloop.poll().await.is_ok() {}
+    }).await;
+    assert!(closed.is_ok());
+    let denials = broker.access_control().unwrap().denials();
+    assert_eq!(denials, mqttd::acl::Denials { publish: 1, subscribe: 2 });
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_second_connect_closes_the_connection() {
+    use bytes::BytesMut;
+    use rumqttc::mqttbytes::v4::{ Connect, Publish };
+
+    let path = std::env::temp_dir().join("mqttd-test-reconnect-credentials.toml");
+    let port = free_port();
+    let mut config = plain_config(port);
+    let credentials = secure(&mut config, &path);
+    let broker = mqttd::start(config, mqttd::default_extensions(), Some(credentials)).unwrap();
+    broker.ready(Duration::from_secs(5)).await.unwrap();
+
+    // rumqttc only connects once, so the packets are written by hand
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let mut packets = BytesMut::new();
+    let mut connect = Connect::new("sensor-1");
+    connect.set_login("sensor-1", "secret");
+    connect.write(&mut packets).unwrap();
+    stream.write_all(&packets.split()).await.unwrap();
+    let mut connack = [0; 4];
+    stream.read_exact(&mut connack).await.unwrap();
+    assert_eq!(connack, [0x20, 2, 0, 0]);
+
+    // the sensor logs in as the hub to command the valves
+    let mut connect = Connect::new("sensor-1");
+    connect.set_login("hub", "secret");
+    connect.write(&mut packets).unwrap();
+    let mut publish = Publish::new("home/watering/watering_needed", rumqttc::mqttbytes::QoS::AtLeastOnce, "true");
+    publish.pkid = 1;
+    publish.write(&mut packets).unwrap();
+    stream.write_all(&packets).await.unwrap();
+
+    // the connection is closed without acknowledging the publication
+    let mut received = Vec::new();
+    let closed = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received)).await;
+    assert!(matches!(closed, Ok(Ok(_))));
+    assert!(received.is_empty());
+    assert_eq!(broker.access_control().unwrap().denials().publish, 0);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+/// Start a broker on a free port that keeps the retained messages in the store
+async fn start_persistent_broker(store: &Path) -> u16 {
+    let port = free_port();
+    let mut extensions = mqttd::default_extensions();
+    extensions.persistence.retained = Some(store.to_path_buf());
+    let broker = mqttd::start(plain_config(port), extensions, None).unwrap();
+    broker.ready(Duration::from_secs(5)).await.unwrap();
+    port
+}
+
+#[tokio::test]
+async fn test_retained_messages_survive_restart() {
+    let store = std::env::temp_dir().join("mqttd-test-retained.toml");
+    let _ = std::fs::remove_file(&store);
+
+    let port = start_persistent_broker(&store).await;
+    let (client, mut eventloop) = AsyncClient::new(MqttOptions::new("hub-retain-test", "127.0.0.1", port), 10);
+    client.publish("settings/home/sensor/interval", QoS::AtLeastOnce, true, "60").await.unwrap();
+    let acknowledged = tokio::time::timeout(Duration::from_secs(5), async {
+        while !matches!(eventloop.poll().await.unwrap(), Event::Incoming(Incoming::PubAck(_))) {}
+    }).await;
+    assert!(acknowledged.is_ok());
+
+    // a new broker with the same store stands in for the restarted one
+    let port = start_persistent_broker(&store).await;
+    let (client, mut eventloop) = AsyncClient::new(MqttOptions::new("esp-retain-test", "127.0.0.1", port), 10);
+    client.subscribe("settings/#", QoS::AtLeastOnce).await.unwrap();
+    let retained = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Event::Incoming(Incoming::Publish(publish)) = eventloop.poll().await.unwrap() {
+                return publish;
+            }
+        }
+    }).await.unwrap();
+    assert!(retained.retain);
+    assert_eq!(retained.topic, "settings/home/sensor/interval");
+    assert_eq!(&retained.payload[..], b"60");
+
+    std::fs::remove_file(store).unwrap();
+}
+
+#[tokio::test]
+async fn test_broker_statistics() {
+    let (port, metrics_port) = (free_port(), free_port());
+    let mut config = mqttd::default_config();
+    config.v5 = None;
+    let overrides = mqttd::Overrides {
+        v4_listen: Some(format!("127.0.0.1:{}", port).parse().unwrap()),
+        metrics_listen: Some(format!("127.0.0.1:{}", metrics_port).parse().unwrap()),
+        ..Default::default()
+    };
+    overrides.apply(&mut config).unwrap();
+    let mut extensions = mqttd::default_extensions();
+    overrides.apply_extensions(&mut extensions);
+    extensions.statistics.interval = 1;
+    let broker = mqttd::start(config, extensions, None).unwrap();
+    broker.ready(Duration::from_secs(5)).await.unwrap();
+
+    let (client, mut eventloop) = AsyncClient::new(MqttOptions::new("dashboard-stats-test", "127.0.0.1", port), 10);
+    client.subscribe("sys/broker/clients/connected", QoS::AtMostOnce).await.unwrap();
+    client.publish("settings/home/sensor/interval", QoS::AtMostOnce, true, "60").await.unwrap();
+    // the retained statistics from before the connection arrive first
+    let connected = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Event::Incoming(Incoming::Publish(publish)) = eventloop.poll().await.unwrap() {
+                if &publish.payload[..] == b"1" {
+                    return;
+                }
+            }
+        }
+    }).await;
+    assert!(connected.is_ok());
+
+    let snapshot = broker.stats().unwrap().snapshot();
+    assert_eq!((snapshot.connections, snapshot.subscriptions, snapshot.retained), (1, 1, 1));
+    assert!(snapshot.received >= 1 && snapshot.sent >= 1);
+
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", metrics_port)).await.unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("\nmqttd_clients_connected 1\n"));
+    assert!(response.contains("\nmqttd_retained_messages 1\n"));
+}